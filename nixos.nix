@@ -6,6 +6,14 @@ with lib;
 let
   cfg = config.services.axyn-matrix;
 
+  settingsFormat = pkgs.formats.toml {};
+
+  configFile = settingsFormat.generate "axyn.toml" ({
+    homeserver_url = cfg.homeserver;
+    inherit (cfg) username;
+    data_directory = "/var/lib/axyn-matrix";
+  } // cfg.settings);
+
 in {
   options.services.axyn-matrix = {
     enable = mkEnableOption "Axyn for Matrix";
@@ -38,6 +46,21 @@ in {
       default = "echo ${escapeShellArg cfg.password}";
       example = "cat /run/secrets/axyn-password";
     };
+
+    settings = mkOption {
+      description = ''
+        Additional settings for the configuration file.
+
+        The password should not be given here, as it would be copied to the
+        Nix store.
+      '';
+      type = settingsFormat.type;
+      default = {};
+      example = {
+        display_name = "Axyn";
+        behaviour.join_on_invite = false;
+      };
+    };
   };
 
   config = {
@@ -59,20 +82,19 @@ in {
 
       path = with pkgs; [ coreutils packages.${system}.default ];
       script = ''
-        password="$({
+        AXYN_PASSWORD="$({
           ${cfg.passwordScript}
         })"
+        export AXYN_PASSWORD
 
+        # Older versions of this module generated a device ID on first startup,
+        # which we reuse so that Axyn doesn't appear as a new device
         if [ -f /var/lib/axyn-matrix/device-id ]; then
-          deviceId="$(cat /var/lib/axyn-matrix/device-id)"
-        else
-          deviceId="$(tr -dc A-Z </dev/urandom | head -c 10)"
-          echo -n "$deviceId" >/var/lib/axyn-matrix/device-id
+          AXYN_DEVICE_ID="$(cat /var/lib/axyn-matrix/device-id)"
+          export AXYN_DEVICE_ID
         fi
 
-        export HOME=/var/lib/axyn-matrix
-
-        axyn ${cfg.homeserver} ${cfg.username} "$password" "$deviceId"
+        axyn ${configFile}
       '';
     };
   };
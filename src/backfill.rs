@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+extern crate matrix_sdk;
+use matrix_sdk::{
+    room::{Joined, MessagesOptions},
+    ruma::{
+        events::{room::message::Relation, AnyMessageLikeEvent, AnyRoomEvent, MessageLikeEvent},
+        OwnedEventId,
+        OwnedUserId,
+    },
+    Client,
+};
+
+use crate::{
+    matrix_body::{get_previous_body, Body, HasBody},
+    store::{Provenance, ResponseStore},
+};
+
+/// Learn from the history of a room, reading backwards from where it was left
+/// off until the start of the room or the limit is reached.
+pub async fn backfill(
+    room: &Joined,
+    client: &Client,
+    database: &ResponseStore,
+    limit: usize,
+) -> anyhow::Result<()> {
+    let room_id = room.room_id().as_str();
+    let own_user_id = client.user_id().await.expect("Getting own user ID");
+
+    let mut progress = match database.backfill_progress(room_id)? {
+        Some(progress) => progress,
+        None => return Ok(()),
+    };
+
+    // Edits are sent after the messages they replace, so they are read first
+    let mut edits: HashMap<OwnedEventId, (OwnedUserId, Body)> = HashMap::new();
+
+    while let Some(token) = progress.token.take() {
+        // Messages are given most recent first
+        let messages = room.messages(MessagesOptions::backward(&token)).await?;
+        for (index, event) in messages.chunk.iter().enumerate() {
+            if progress.messages >= limit {
+                break;
+            }
+
+            let event = match event.event.deserialize() {
+                Ok(AnyRoomEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                    MessageLikeEvent::Original(event),
+                ))) => event,
+                _ => continue,
+            };
+            if event.sender == own_user_id {
+                continue;
+            }
+
+            if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+                // Only the latest edit is kept
+                if let Some(body) = replacement.new_content.get_body() {
+                    edits
+                        .entry(replacement.event_id.clone())
+                        .or_insert((event.sender.clone(), body));
+                }
+                continue;
+            }
+
+            let mut body = match event.get_body() {
+                Some(body) => body,
+                None => continue,
+            };
+            progress.messages += 1;
+
+            // Only the original sender may edit a message. Responses to it have
+            // already been learned, so are moved to its new content
+            if let Some((sender, edited)) = edits.remove(&event.event_id) {
+                if sender == event.sender {
+                    database.replace_event(event.event_id.as_str(), &edited)?;
+                    body = edited;
+                }
+            }
+
+            // Events which were learned from before being stopped part way
+            // through a chunk are read again when carrying on
+            if database.has_learned_from(event.event_id.as_str())? {
+                continue;
+            }
+
+            let events_before = &messages.chunk[index + 1..];
+            let previous =
+                get_previous_body(&event.event_id, &event.content, events_before, client, room)
+                    .await?;
+
+            if let Some((previous_body, previous_event_id)) = previous {
+                let provenance = Provenance {
+                    event_id: Some(event.event_id.to_string()),
+                    prompt_event_id: Some(previous_event_id.to_string()),
+                    room_id: Some(room_id.to_string()),
+                    sender: Some(event.sender.to_string()),
+                    timestamp: Some(event.origin_server_ts.0.into()),
+                    prompt: None,
+                    source: None,
+                };
+                database.insert(&previous_body.plain, body, provenance)?;
+            }
+        }
+
+        // There is no end token once the start of the room is reached
+        if !messages.chunk.is_empty() && progress.messages < limit {
+            progress.token = messages.end;
+        }
+        database.set_backfill_progress(room_id, &progress)?;
+    }
+
+    println!(
+        "Learned from {} old messages in {}",
+        progress.messages, room_id
+    );
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    config::Config,
+    corpus::{read_corpus, write_jsonl_pair, CorpusFormat, Pair},
+    store::{ResponseReader, ResponseStore},
+};
+
+/// Remove every learned response with the given text.
+pub fn forget(config: &Config, message: &str) -> anyhow::Result<()> {
+    let database = ResponseStore::load(config)?;
+    let removed = database.remove_by_content(message)?;
+    println!("Removed {} responses", removed);
+    Ok(())
+}
+
+/// Remove the learned responses which came from or responded to the given
+/// event.
+pub fn forget_event(config: &Config, event_id: &str) -> anyhow::Result<()> {
+    let database = ResponseStore::load(config)?;
+    let removed = database.remove_by_event(event_id)?;
+    println!("Removed {} responses", removed);
+    Ok(())
+}
+
+/// Learn every prompt and response pair in a corpus.
+pub fn import(config: &Config, format: &str, path: &Path) -> anyhow::Result<()> {
+    let format: CorpusFormat = format.parse()?;
+    let pairs = read_corpus(format, path)?;
+    let total = pairs.len();
+
+    let database = ResponseStore::load(config)?;
+    println!("Importing {} responses", total);
+    let (imported, duplicates) = database.insert_all(
+        pairs
+            .into_iter()
+            .map(|pair| (pair.prompt, pair.response, pair.provenance)),
+    )?;
+    println!(
+        "Imported {} responses, skipping {} already imported and {} without known words",
+        imported,
+        duplicates,
+        total - imported - duplicates
+    );
+    Ok(())
+}
+
+/// Write every learned response to a JSONL file, which can be imported again.
+pub fn export(config: &Config, path: &Path) -> anyhow::Result<()> {
+    let database = ResponseReader::open(config)?;
+    let mut file = BufWriter::new(File::create(path)?);
+
+    let mut exported = 0;
+    let mut skipped = 0;
+    for response in database.responses() {
+        let (prompt, body, provenance) = response?;
+
+        // Prompts learned before their text was recorded can't be embedded
+        // again, so there is no way to import their responses
+        let prompt = match prompt {
+            Some(prompt) => prompt,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let pair = Pair {
+            prompt,
+            response: body,
+            provenance,
+        };
+        write_jsonl_pair(&mut file, pair)?;
+        exported += 1;
+    }
+    file.flush()?;
+
+    println!(
+        "Exported {} responses, skipping {} without a recorded prompt",
+        exported, skipped
+    );
+    Ok(())
+}
+
+/// Embed every learned prompt again, such as to take account of the words
+/// learned since with SIF weighting.
+pub fn reembed(config: &Config) -> anyhow::Result<()> {
+    let mut database = ResponseStore::load(config)?;
+    println!("Embedding prompts again");
+    let (reembedded, kept, dropped) = database.reembed()?;
+    println!(
+        "Embedded {} prompts again, kept the previous vectors of {} others, and dropped {} whose vectors don't match the word vectors",
+        reembedded, kept, dropped
+    );
+    Ok(())
+}
@@ -0,0 +1,564 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    io,
+    iter,
+    path::{Path, PathBuf},
+};
+
+extern crate dirs;
+
+extern crate quick_error;
+use quick_error::quick_error;
+
+extern crate serde;
+use serde::Deserialize;
+
+extern crate toml;
+use toml::value::{Table, Value};
+
+extern crate url;
+use url::Url;
+
+use crate::{
+    store::Distance,
+    vectors::{VectorFormat, Weighting},
+};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ConfigError {
+        ReadError(path: PathBuf, error: io::Error) {
+            source(error)
+            display("failed to read {}: {}", path.display(), error)
+        }
+        ParseError(error: toml::de::Error) {
+            from(error: toml::de::Error) -> (error)
+            source(error)
+            display("invalid configuration: {}", error)
+        }
+        NotATable {
+            display("expected the configuration to be a table of settings")
+        }
+        ExpectedTable(setting: &'static str) {
+            display("expected `{}` to be a table of settings", setting)
+        }
+        MissingField(field: &'static str) {
+            display("the `{}` setting must be set to connect to Matrix", field)
+        }
+        EmptyField(field: &'static str) {
+            display("the `{}` setting must not be empty", field)
+        }
+        OutOfRange(field: &'static str) {
+            display("the `{}` setting is out of range", field)
+        }
+        MissingVectorsPath {
+            display("`vectors.path` is not set, and this build of Axyn has no default")
+        }
+        MissingPassword {
+            display("expected one of `password` or `password_file` to be set")
+        }
+        ConflictingPasswords {
+            display("only one of `password` and `password_file` may be set")
+        }
+        MissingHomeDirectory {
+            display("a path starts with `~`, but the home directory could not be found")
+        }
+        MissingDataDirectory {
+            display("`data_directory` is not set, and the user's data directory could not be found")
+        }
+        InvalidRoomId(room_id: String) {
+            display("expected `{}` to be a room ID, like `!room:example.org`", room_id)
+        }
+    }
+}
+
+/// Settings which may be given as environment variables instead of in the
+/// configuration file. For example, `password` may be given as `AXYN_PASSWORD`
+/// and `vectors.path` as `AXYN_VECTORS_PATH`.
+const ENVIRONMENT_SETTINGS: &[&str] = &[
+    "homeserver_url",
+    "username",
+    "password",
+    "password_file",
+    "device_id",
+    "data_directory",
+    "vectors.path",
+    "vectors.format",
+    "vectors.cache",
+    "display_name",
+    "avatar",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    homeserver_url: Option<Url>,
+    username: Option<String>,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    device_id: Option<String>,
+    data_directory: Option<PathBuf>,
+    #[serde(default)]
+    vectors: VectorsFile,
+    #[serde(default)]
+    embedding: EmbeddingConfig,
+    #[serde(default)]
+    responses: ResponsesConfig,
+    #[serde(default = "default_display_name")]
+    display_name: String,
+    avatar: Option<PathBuf>,
+    #[serde(default)]
+    behaviour: Behaviour,
+    #[serde(default)]
+    rooms: HashMap<String, RoomConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VectorsFile {
+    path: Option<PathBuf>,
+    #[serde(default)]
+    format: VectorFormat,
+    cache: Option<PathBuf>,
+    #[serde(default)]
+    subword_buckets: usize,
+}
+
+fn default_display_name() -> String {
+    "Axyn".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Behaviour {
+    /// Whether to accept invitations to rooms.
+    pub join_on_invite: bool,
+    /// Whether to send read receipts for messages which have been processed.
+    pub read_receipts: bool,
+    /// Whether to learn from rooms which anyone can't join, unless the room
+    /// says otherwise.
+    pub learn_from_private_rooms: bool,
+    /// Whether to learn from end-to-end encrypted rooms, unless the room says
+    /// otherwise.
+    pub learn_from_encrypted_rooms: bool,
+    /// How many messages from before Axyn joined a room to learn from, or zero
+    /// to only learn from new messages.
+    pub backfill_messages: usize,
+    /// How old a message may be, in seconds, and still be replied to. Older
+    /// messages, such as those sent while Axyn was offline, are only learned
+    /// from.
+    pub max_reply_age: u64,
+    /// Which messages to reply to, unless the room says otherwise.
+    pub reply_to: ReplyTo,
+    /// The chance of replying to a message which may be replied to.
+    pub reply_probability: f64,
+    /// How long to wait after replying in a room before replying there
+    /// again, in seconds.
+    pub reply_cooldown: u64,
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Behaviour {
+            join_on_invite: true,
+            read_receipts: true,
+            learn_from_private_rooms: false,
+            learn_from_encrypted_rooms: false,
+            backfill_messages: 0,
+            max_reply_age: 60,
+            reply_to: ReplyTo::default(),
+            reply_probability: 1.0,
+            reply_cooldown: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyTo {
+    /// Every message.
+    All,
+    /// Messages in rooms which are marked as direct chats.
+    Direct,
+    /// Messages which mention Axyn or reply to it.
+    Mentions,
+}
+
+impl Default for ReplyTo {
+    fn default() -> Self {
+        ReplyTo::All
+    }
+}
+
+/// The directory where all of Axyn's state is kept.
+///
+/// Every file which Axyn writes should be located through this type, so that
+/// separate instances never share state.
+#[derive(Clone, Debug)]
+pub struct DataDirectory(PathBuf);
+
+impl DataDirectory {
+    pub fn create(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.0)
+    }
+
+    /// Location of the Matrix SDK's state and encryption stores.
+    pub fn matrix_store(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn session(&self) -> PathBuf {
+        self.0.join("session")
+    }
+
+    pub fn responses(&self) -> PathBuf {
+        self.0.join("responses")
+    }
+
+    pub fn index(&self) -> PathBuf {
+        self.0.join("index")
+    }
+
+    pub fn vectors_cache(&self) -> PathBuf {
+        self.0.join("vectors")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingConfig {
+    /// How the vectors of words are weighted when combining them into the
+    /// vector of an utterance.
+    pub weighting: Weighting,
+    /// The smoothing parameter of SIF weighting. Smaller values favour rare
+    /// words more strongly.
+    pub smoothing: f64,
+    /// Whether to remove the direction which all prompts have in common from
+    /// their vectors.
+    pub remove_common_component: bool,
+    /// How prompts are compared when looking for the closest one.
+    pub distance: Distance,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        EmbeddingConfig {
+            weighting: Weighting::default(),
+            smoothing: 1e-3,
+            remove_common_component: false,
+            distance: Distance::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponsesConfig {
+    /// How far the closest learned prompt may be from a message for Axyn to
+    /// respond to it, or `None` to always respond.
+    pub max_distance: Option<f64>,
+    /// How many of the closest learned prompts to choose a response from.
+    pub candidates: usize,
+    /// How much more likely responses to closer prompts are to be chosen.
+    /// Lower temperatures favour the closest prompt more strongly.
+    pub temperature: f64,
+    /// Whether responses learned in rooms without their own setting may be
+    /// used in other rooms.
+    pub privacy: Privacy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privacy {
+    /// Responses may be used in any room.
+    Shared,
+    /// Responses may only be used in the room they were learned in, and the
+    /// rooms it shares with.
+    Private,
+}
+
+impl Default for Privacy {
+    fn default() -> Self {
+        Privacy::Shared
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomConfig {
+    /// Overrides `responses.privacy` for responses learned in this room.
+    pub privacy: Option<Privacy>,
+    /// Rooms which may use responses learned in this room when it is
+    /// private.
+    pub share_with: Vec<String>,
+    /// Whether to learn from this room, overriding both the `behaviour`
+    /// settings and the room's own settings.
+    pub learn: Option<bool>,
+    /// These override the `behaviour` settings of the same names, and the
+    /// room's own settings.
+    pub reply_to: Option<ReplyTo>,
+    pub reply_probability: Option<f64>,
+    pub reply_cooldown: Option<u64>,
+}
+
+impl Default for ResponsesConfig {
+    fn default() -> Self {
+        ResponsesConfig {
+            max_distance: None,
+            candidates: 1,
+            temperature: 0.1,
+            privacy: Privacy::default(),
+        }
+    }
+}
+
+pub struct VectorsConfig {
+    pub path: PathBuf,
+    pub format: VectorFormat,
+    /// Where to keep the binary copy of the vectors which is loaded at
+    /// startup.
+    pub cache_path: PathBuf,
+    /// Number of buckets used to approximate vectors for unknown words from
+    /// their character n-grams, or zero to disable this.
+    ///
+    /// Each bucket holds the mean vector of the known words which contain its
+    /// n-grams, which is only a rough stand in for fastText's trained n-gram
+    /// vectors. The sums of every bucket are held in memory while the cache is
+    /// being written.
+    pub subword_buckets: usize,
+}
+
+/// The account which Axyn logs in to.
+pub struct Credentials {
+    pub homeserver_url: Url,
+    pub username: String,
+    pub password: String,
+}
+
+pub struct Config {
+    // Only needed to connect to Matrix, so they are checked by `credentials`
+    homeserver_url: Option<Url>,
+    username: Option<String>,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    pub device_id: Option<String>,
+    pub data_directory: DataDirectory,
+    pub vectors: VectorsConfig,
+    pub embedding: EmbeddingConfig,
+    pub responses: ResponsesConfig,
+    pub display_name: String,
+    pub avatar_path: Option<PathBuf>,
+    pub behaviour: Behaviour,
+    /// Settings for individual rooms, by room ID.
+    pub rooms: HashMap<String, RoomConfig>,
+}
+
+pub fn is_probability(value: f64) -> bool {
+    (0.0..=1.0).contains(&value)
+}
+
+fn default_data_directory() -> Result<PathBuf, ConfigError> {
+    // Axyn kept its state in the home directory before this was configurable
+    if let Some(home) = dirs::home_dir() {
+        if home.join("responses").exists() {
+            return Ok(home);
+        }
+    }
+
+    let data = dirs::data_dir().ok_or(ConfigError::MissingDataDirectory)?;
+    Ok(data.join("axyn"))
+}
+
+/// Replace a leading `~` with the home directory, as a shell would.
+fn expand_home(path: PathBuf) -> Result<PathBuf, ConfigError> {
+    match path.strip_prefix("~") {
+        Ok(rest) => {
+            let home = dirs::home_dir().ok_or(ConfigError::MissingHomeDirectory)?;
+            Ok(home.join(rest))
+        }
+        Err(_) => Ok(path),
+    }
+}
+
+fn expand_optional_home(path: Option<PathBuf>) -> Result<Option<PathBuf>, ConfigError> {
+    path.map(expand_home).transpose()
+}
+
+fn read_table(path: &Path) -> Result<Table, ConfigError> {
+    let contents =
+        fs::read_to_string(path).map_err(|error| ConfigError::ReadError(path.to_owned(), error))?;
+
+    match contents.parse()? {
+        Value::Table(table) => Ok(table),
+        _ => Err(ConfigError::NotATable),
+    }
+}
+
+fn apply_environment(root: &mut Table) -> Result<(), ConfigError> {
+    for &setting in ENVIRONMENT_SETTINGS {
+        let variable = format!("AXYN_{}", setting.replace('.', "_").to_uppercase());
+
+        if let Ok(value) = env::var(variable) {
+            let mut table = &mut *root;
+            let mut keys = setting.split('.').peekable();
+
+            while let Some(key) = keys.next() {
+                if keys.peek().is_none() {
+                    table.insert(key.to_string(), Value::String(value));
+                    break;
+                }
+
+                let child = table
+                    .entry(key)
+                    .or_insert_with(|| Value::Table(Table::new()));
+                table = match child {
+                    Value::Table(child) => child,
+                    _ => return Err(ConfigError::ExpectedTable(key)),
+                };
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_password(
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+) -> Result<String, ConfigError> {
+    match (password, password_file) {
+        (Some(password), None) => Ok(password),
+        (None, Some(path)) => {
+            let password =
+                fs::read_to_string(&path).map_err(|error| ConfigError::ReadError(path, error))?;
+            // Files usually end with a newline which is not part of the password
+            Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+        }
+        (None, None) => Err(ConfigError::MissingPassword),
+        (Some(_), Some(_)) => Err(ConfigError::ConflictingPasswords),
+    }
+}
+
+impl Config {
+    /// Load the configuration from an optional TOML file, with settings
+    /// from the environment taking precedence.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut table = match path {
+            Some(path) => read_table(path)?,
+            None => Table::new(),
+        };
+        apply_environment(&mut table)?;
+
+        let file: ConfigFile = Value::Table(table).try_into()?;
+
+        if file.username.as_deref() == Some("") {
+            return Err(ConfigError::EmptyField("username"));
+        }
+        if file.display_name.is_empty() {
+            return Err(ConfigError::EmptyField("display_name"));
+        }
+        if file.device_id.as_deref() == Some("") {
+            return Err(ConfigError::EmptyField("device_id"));
+        }
+        if file.data_directory.as_deref() == Some(Path::new("")) {
+            return Err(ConfigError::EmptyField("data_directory"));
+        }
+
+        if file.embedding.smoothing.is_nan() || file.embedding.smoothing <= 0.0 {
+            return Err(ConfigError::OutOfRange("embedding.smoothing"));
+        }
+        if file.responses.candidates == 0 {
+            return Err(ConfigError::OutOfRange("responses.candidates"));
+        }
+        if file.responses.temperature.is_nan() || file.responses.temperature <= 0.0 {
+            return Err(ConfigError::OutOfRange("responses.temperature"));
+        }
+        if let Some(max_distance) = file.responses.max_distance {
+            if max_distance.is_nan() || max_distance < 0.0 {
+                return Err(ConfigError::OutOfRange("responses.max_distance"));
+            }
+        }
+
+        if !is_probability(file.behaviour.reply_probability) {
+            return Err(ConfigError::OutOfRange("behaviour.reply_probability"));
+        }
+
+        for (room_id, room) in &file.rooms {
+            for room_id in iter::once(room_id).chain(&room.share_with) {
+                if !room_id.starts_with('!') {
+                    return Err(ConfigError::InvalidRoomId(room_id.clone()));
+                }
+            }
+
+            if let Some(reply_probability) = room.reply_probability {
+                if !is_probability(reply_probability) {
+                    return Err(ConfigError::OutOfRange("rooms.reply_probability"));
+                }
+            }
+        }
+
+        // The build may provide a default set of vectors
+        let vectors_path = expand_optional_home(file.vectors.path)?
+            .or_else(|| option_env!("WORD2VEC_DATA").map(PathBuf::from))
+            .ok_or(ConfigError::MissingVectorsPath)?;
+
+        let data_directory = match file.data_directory {
+            Some(path) => expand_home(path)?,
+            None => default_data_directory()?,
+        };
+        let data_directory = DataDirectory(data_directory);
+        let vectors_cache_path = expand_optional_home(file.vectors.cache)?
+            .unwrap_or_else(|| data_directory.vectors_cache());
+
+        // The build may also provide a default avatar
+        let avatar_path = expand_optional_home(file.avatar)?
+            .or_else(|| option_env!("AVATAR_PNG").map(PathBuf::from));
+
+        Ok(Config {
+            homeserver_url: file.homeserver_url,
+            username: file.username,
+            password: file.password,
+            password_file: expand_optional_home(file.password_file)?,
+            device_id: file.device_id,
+            data_directory,
+            vectors: VectorsConfig {
+                path: vectors_path,
+                format: file.vectors.format,
+                cache_path: vectors_cache_path,
+                subword_buckets: file.vectors.subword_buckets,
+            },
+            embedding: file.embedding,
+            responses: file.responses,
+            display_name: file.display_name,
+            avatar_path,
+            behaviour: file.behaviour,
+            rooms: file.rooms,
+        })
+    }
+
+    /// Check the settings needed to log in, and read the password. Commands
+    /// which only use the database don't need these.
+    pub fn credentials(&self) -> Result<Credentials, ConfigError> {
+        let homeserver_url = self
+            .homeserver_url
+            .clone()
+            .ok_or(ConfigError::MissingField("homeserver_url"))?;
+        let username = self
+            .username
+            .clone()
+            .ok_or(ConfigError::MissingField("username"))?;
+
+        let password = read_password(self.password.clone(), self.password_file.clone())?;
+        if password.is_empty() {
+            return Err(ConfigError::EmptyField("password"));
+        }
+
+        Ok(Credentials {
+            homeserver_url,
+            username,
+            password,
+        })
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+extern crate quick_error;
+use quick_error::quick_error;
+
+extern crate serde;
+use serde::{Deserialize, Serialize};
+
+extern crate serde_json;
+
+use crate::{matrix_body::Body, store::Provenance};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CorpusError {
+        ReadError(path: PathBuf, error: io::Error) {
+            source(error)
+            display("failed to read {}: {}", path.display(), error)
+        }
+        JsonError(line: usize, error: serde_json::Error) {
+            source(error)
+            display("invalid JSON on line {}: {}", line, error)
+        }
+        WriteError(error: io::Error) {
+            source(error)
+            display("failed to write corpus: {}", error)
+        }
+        MissingTab(line: usize) {
+            display("expected a tab between the prompt and response on line {}", line)
+        }
+        InvalidConversation(line: usize) {
+            display("expected a list of line IDs at the end of conversation {}", line)
+        }
+        UnknownFormat(format: String) {
+            display("unknown corpus format `{}`, expected `tsv`, `jsonl` or `cornell`", format)
+        }
+    }
+}
+
+/// Separates the fields of the Cornell Movie-Dialogs Corpus.
+const CORNELL_SEPARATOR: &str = " +++$+++ ";
+
+#[derive(Clone, Copy, Debug)]
+pub enum CorpusFormat {
+    /// One prompt and response per line, separated by a tab.
+    Tsv,
+    /// One object per line, with `prompt`, `response` and optionally `html`
+    /// and the provenance of the response. This is also the format which
+    /// responses are exported in.
+    Jsonl,
+    /// The directory of the Cornell Movie-Dialogs Corpus, where each line of
+    /// a conversation is a response to the line before it.
+    Cornell,
+}
+
+impl FromStr for CorpusFormat {
+    type Err = CorpusError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "tsv" => Ok(CorpusFormat::Tsv),
+            "jsonl" => Ok(CorpusFormat::Jsonl),
+            "cornell" => Ok(CorpusFormat::Cornell),
+            _ => Err(CorpusError::UnknownFormat(format.to_string())),
+        }
+    }
+}
+
+/// A response, along with the prompt it responded to.
+#[derive(Debug)]
+pub struct Pair {
+    pub prompt: String,
+    pub response: Body,
+    pub provenance: Provenance,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    prompt: String,
+    response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    event_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prompt_event_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sender: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+fn plain_pair(prompt: &str, response: &str, source: String) -> Pair {
+    Pair {
+        prompt: prompt.to_string(),
+        response: Body {
+            plain: response.to_string(),
+            html: None,
+        },
+        provenance: Provenance {
+            source: Some(source),
+            ..Provenance::default()
+        },
+    }
+}
+
+/// Identifies a prompt and response from a corpus, so that they aren't
+/// imported twice however the corpus is named or rearranged.
+fn content_source(prompt: &str, response: &str) -> String {
+    // FNV-1a, which unlike the standard library's hasher is the same in every
+    // build. The length separates the prompt from the response
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let bytes = (prompt.len() as u64).to_be_bytes();
+    for byte in bytes
+        .iter()
+        .chain(prompt.as_bytes())
+        .chain(response.as_bytes())
+    {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("corpus:{:016x}", hash)
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>, CorpusError> {
+    let read_error = |error| CorpusError::ReadError(path.to_owned(), error);
+
+    let file = File::open(path).map_err(read_error)?;
+    BufReader::new(file)
+        .lines()
+        .collect::<Result<_, _>>()
+        .map_err(read_error)
+}
+
+// The Cornell corpus is encoded as ISO-8859-1, where every byte is the
+// character with the same code point
+fn read_latin1_lines(path: &Path) -> Result<Vec<String>, CorpusError> {
+    let read_error = |error| CorpusError::ReadError(path.to_owned(), error);
+
+    let file = File::open(path).map_err(read_error)?;
+    BufReader::new(file)
+        .split(b'\n')
+        .map(|line| {
+            let line = line.map_err(read_error)?;
+            let line: String = line.into_iter().map(char::from).collect();
+            Ok(line.trim_end_matches('\r').to_string())
+        })
+        .collect()
+}
+
+fn parse_tsv(lines: &[String]) -> Result<Vec<Pair>, CorpusError> {
+    let mut pairs = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (prompt, response) = line
+            .split_once('\t')
+            .ok_or(CorpusError::MissingTab(index + 1))?;
+        pairs.push(plain_pair(
+            prompt,
+            response,
+            content_source(prompt, response),
+        ));
+    }
+
+    Ok(pairs)
+}
+
+fn parse_jsonl(lines: &[String]) -> Result<Vec<Pair>, CorpusError> {
+    let mut pairs = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let pair: JsonPair =
+            serde_json::from_str(line).map_err(|error| CorpusError::JsonError(index + 1, error))?;
+        // Exported responses keep where they were first imported from
+        let source = pair
+            .source
+            .unwrap_or_else(|| content_source(&pair.prompt, &pair.response));
+        pairs.push(Pair {
+            prompt: pair.prompt,
+            response: Body {
+                plain: pair.response,
+                html: pair.html,
+            },
+            provenance: Provenance {
+                event_id: pair.event_id,
+                prompt_event_id: pair.prompt_event_id,
+                room_id: pair.room_id,
+                sender: pair.sender,
+                timestamp: pair.timestamp,
+                prompt: None,
+                source: Some(source),
+            },
+        });
+    }
+
+    Ok(pairs)
+}
+
+fn parse_cornell(
+    movie_lines: &[String],
+    conversations: &[String],
+) -> Result<Vec<Pair>, CorpusError> {
+    // Each line is «line ID», «character ID», «movie ID», «character name»,
+    // «text»
+    let mut texts = HashMap::new();
+    for line in movie_lines {
+        let fields: Vec<&str> = line.splitn(5, CORNELL_SEPARATOR).collect();
+        if let [line_id, _, _, _, text] = fields[..] {
+            texts.insert(line_id, text);
+        }
+    }
+
+    // Each conversation is «character ID», «character ID», «movie ID», and a
+    // list of line IDs such as ['L194', 'L195']
+    let mut pairs = Vec::new();
+    for (index, conversation) in conversations.iter().enumerate() {
+        if conversation.trim().is_empty() {
+            continue;
+        }
+
+        let line_ids = conversation
+            .rsplit(CORNELL_SEPARATOR)
+            .next()
+            .and_then(|line_ids| line_ids.trim().strip_prefix('['))
+            .and_then(|line_ids| line_ids.strip_suffix(']'))
+            .ok_or(CorpusError::InvalidConversation(index + 1))?;
+
+        let lines: Vec<(&str, Option<&&str>)> = line_ids
+            .split(',')
+            .map(|line_id| {
+                let line_id = line_id.trim().trim_matches('\'');
+                (line_id, texts.get(line_id))
+            })
+            .collect();
+
+        // Lines which are missing from the corpus break the conversation
+        for window in lines.windows(2) {
+            if let [(prompt_id, Some(prompt)), (response_id, Some(response))] = window {
+                let source = format!("corpus:cornell:{}:{}", prompt_id, response_id);
+                pairs.push(plain_pair(prompt, response, source));
+            }
+        }
+    }
+
+    Ok(pairs)
+}
+
+/// Read every prompt and response pair from a corpus.
+pub fn read_corpus(format: CorpusFormat, path: &Path) -> Result<Vec<Pair>, CorpusError> {
+    match format {
+        CorpusFormat::Tsv => parse_tsv(&read_lines(path)?),
+        CorpusFormat::Jsonl => parse_jsonl(&read_lines(path)?),
+        CorpusFormat::Cornell => parse_cornell(
+            &read_latin1_lines(&path.join("movie_lines.txt"))?,
+            &read_latin1_lines(&path.join("movie_conversations.txt"))?,
+        ),
+    }
+}
+
+/// Write a pair in the JSONL format, including its provenance.
+pub fn write_jsonl_pair(writer: &mut impl Write, pair: Pair) -> Result<(), CorpusError> {
+    let pair = JsonPair {
+        prompt: pair.prompt,
+        response: pair.response.plain,
+        html: pair.response.html,
+        event_id: pair.provenance.event_id,
+        prompt_event_id: pair.provenance.prompt_event_id,
+        room_id: pair.provenance.room_id,
+        sender: pair.provenance.sender,
+        timestamp: pair.provenance.timestamp,
+        source: pair.provenance.source,
+    };
+
+    serde_json::to_writer(&mut *writer, &pair)
+        .map_err(|error| CorpusError::WriteError(error.into()))?;
+    writer.write_all(b"\n").map_err(CorpusError::WriteError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    fn summarise(pairs: &[Pair]) -> Vec<(&str, &str, Option<&str>)> {
+        pairs
+            .iter()
+            .map(|pair| {
+                (
+                    pair.prompt.as_str(),
+                    pair.response.plain.as_str(),
+                    pair.provenance.source.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_tsv() {
+        let pairs = parse_tsv(&lines("Hi\tHello!\n\nHow are you?\tFine, thanks")).unwrap();
+
+        let hi = content_source("Hi", "Hello!");
+        let how = content_source("How are you?", "Fine, thanks");
+        assert_eq!(
+            summarise(&pairs),
+            [
+                ("Hi", "Hello!", Some(hi.as_str())),
+                ("How are you?", "Fine, thanks", Some(how.as_str())),
+            ]
+        );
+    }
+
+    #[test]
+    fn sources_depend_only_on_content() {
+        let before = parse_tsv(&lines("Hi\tHello!\nBye\tSee you")).unwrap();
+        let after = parse_tsv(&lines("Hey\tHello\nBye\tSee you\nHi\tHello!")).unwrap();
+
+        assert_eq!(before[0].provenance.source, after[2].provenance.source);
+        assert_eq!(before[1].provenance.source, after[1].provenance.source);
+        assert_ne!(after[0].provenance.source, after[2].provenance.source);
+        assert_ne!(content_source("ab", "c"), content_source("a", "bc"));
+        assert_eq!(content_source("Hi", "Hello!"), "corpus:b5ed7dac6287bd7f");
+    }
+
+    #[test]
+    fn rejects_tsv_lines_without_a_tab() {
+        let error = parse_tsv(&lines("Hi\tHello!\nHi")).unwrap_err();
+        assert!(matches!(error, CorpusError::MissingTab(2)));
+    }
+
+    #[test]
+    fn parses_jsonl() {
+        let pairs = parse_jsonl(&lines(concat!(
+            r#"{"prompt": "Hi", "response": "Hello!", "html": "<b>Hello!</b>", "#,
+            r#""event_id": "$response", "room_id": "!room:example.org"}"#,
+            "\n",
+            r#"{"prompt": "Bye", "response": "See you", "source": "corpus:old.tsv:7"}"#,
+        )))
+        .unwrap();
+
+        let hi = content_source("Hi", "Hello!");
+        assert_eq!(
+            summarise(&pairs),
+            [
+                ("Hi", "Hello!", Some(hi.as_str())),
+                ("Bye", "See you", Some("corpus:old.tsv:7")),
+            ]
+        );
+        assert_eq!(pairs[0].response.html.as_deref(), Some("<b>Hello!</b>"));
+        assert_eq!(pairs[0].provenance.event_id.as_deref(), Some("$response"));
+        assert_eq!(
+            pairs[0].provenance.room_id.as_deref(),
+            Some("!room:example.org")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_jsonl() {
+        let error = parse_jsonl(&lines(
+            "{\"prompt\": \"Hi\", \"response\": \"Hello!\"}\n{\"prompt\": \"Hi\"}",
+        ))
+        .unwrap_err();
+        assert!(matches!(error, CorpusError::JsonError(2, _)));
+    }
+
+    #[test]
+    fn exported_jsonl_can_be_imported() {
+        let pair = Pair {
+            prompt: "Hi".to_string(),
+            response: Body {
+                plain: "Hello!".to_string(),
+                html: None,
+            },
+            provenance: Provenance {
+                sender: Some("@someone:example.org".to_string()),
+                source: Some("corpus:responses.tsv:1".to_string()),
+                ..Provenance::default()
+            },
+        };
+
+        let mut output = Vec::new();
+        write_jsonl_pair(&mut output, pair).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let pairs = parse_jsonl(&lines(&output)).unwrap();
+        assert_eq!(
+            summarise(&pairs),
+            [("Hi", "Hello!", Some("corpus:responses.tsv:1"))]
+        );
+        assert_eq!(
+            pairs[0].provenance.sender.as_deref(),
+            Some("@someone:example.org")
+        );
+    }
+
+    #[test]
+    fn parses_cornell_conversations() {
+        let movie_lines = lines(concat!(
+            "L1 +++$+++ u0 +++$+++ m0 +++$+++ A +++$+++ Hi\n",
+            "L2 +++$+++ u1 +++$+++ m0 +++$+++ B +++$+++ Hello!\n",
+            "L3 +++$+++ u0 +++$+++ m0 +++$+++ A +++$+++ How are you?\n",
+            "L5 +++$+++ u0 +++$+++ m0 +++$+++ A +++$+++ Bye\n",
+            "L6 +++$+++ u1 +++$+++ m0 +++$+++ B +++$+++ See you",
+        ));
+        let conversations = lines(concat!(
+            "u0 +++$+++ u1 +++$+++ m0 +++$+++ ['L1', 'L2', 'L3']\n",
+            "u0 +++$+++ u1 +++$+++ m0 +++$+++ ['L4', 'L5', 'L6']",
+        ));
+
+        let pairs = parse_cornell(&movie_lines, &conversations).unwrap();
+        assert_eq!(
+            summarise(&pairs),
+            [
+                ("Hi", "Hello!", Some("corpus:cornell:L1:L2")),
+                ("Hello!", "How are you?", Some("corpus:cornell:L2:L3")),
+                ("Bye", "See you", Some("corpus:cornell:L5:L6")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_cornell_conversations_without_line_ids() {
+        let conversations = lines("u0 +++$+++ u1 +++$+++ m0 +++$+++ L1, L2");
+        let error = parse_cornell(&[], &conversations).unwrap_err();
+        assert!(matches!(error, CorpusError::InvalidConversation(1)));
+    }
+}
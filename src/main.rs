@@ -1,29 +1,104 @@
+mod backfill;
+mod commands;
+mod config;
+mod corpus;
 mod matrix_api;
 mod matrix_body;
 mod matrix_event_handlers;
+mod room_settings;
+mod session;
 mod store;
+mod subwords;
+mod tokenizer;
+mod vector_cache;
 mod vectors;
 
-use std::{env, process::exit};
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::exit,
+};
 
-use crate::matrix_event_handlers::login_and_sync;
+use crate::{
+    commands::{export, forget, forget_event, import, reembed},
+    config::Config,
+    matrix_event_handlers::login_and_sync,
+};
 
 extern crate anyhow;
 
 extern crate tokio;
 
+const USAGE: &str = "Usage: axyn [configuration file] [command]
+
+Commands:
+    forget «message»        Forget every learned response with this text
+    forget-event «event ID» Forget responses learned from or to this event
+    import «format» «path»  Learn the responses in a corpus, where the format is
+                            tsv, jsonl or cornell
+    export «path»           Write every learned response to a JSONL file
+    reembed                 Embed every learned prompt again, such as to take
+                            account of the words learned since with SIF
+
+The configuration file may be left out if AXYN_CONFIG is set. Without a command,
+Axyn logs in and responds to messages. The database can only be used by one
+process at a time, so stop Axyn before running a command.";
+
+const COMMANDS: [&str; 5] = ["forget", "forget-event", "import", "export", "reembed"];
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    if let (Some(homeserver_url), Some(username), Some(password), Some(device_id)) = (
-        env::args().nth(1),
-        env::args().nth(2),
-        env::args().nth(3),
-        env::args().nth(4),
-    ) {
-        login_and_sync(homeserver_url, &username, &password, &device_id).await?;
-        Ok(())
-    } else {
-        eprintln!("Required arguments: «homeserver URL» «username» «password» «device ID»");
-        exit(1)
+    let mut arguments = env::args_os().skip(1).peekable();
+
+    // The configuration file can be left out when it is given by the
+    // environment, in which case the arguments start with the command
+    let is_command = |argument: &OsString| COMMANDS.iter().any(|command| argument == *command);
+    let config_path = match arguments.peek() {
+        Some(argument) if !is_command(argument) => arguments.next(),
+        _ => env::var_os("AXYN_CONFIG"),
     }
+    .map(PathBuf::from);
+
+    let command: Vec<String> = match arguments.map(|argument| argument.into_string()).collect() {
+        Ok(command) => command,
+        Err(_) => {
+            eprintln!("Arguments must be valid Unicode");
+            exit(1)
+        }
+    };
+
+    let config = match Config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Error loading configuration: {}", error);
+            eprintln!("{}", USAGE);
+            exit(1)
+        }
+    };
+
+    let command: Vec<&str> = command.iter().map(String::as_str).collect();
+    match command.as_slice() {
+        [] => {
+            let credentials = match config.credentials() {
+                Ok(credentials) => credentials,
+                Err(error) => {
+                    eprintln!("Error loading configuration: {}", error);
+                    exit(1)
+                }
+            };
+            login_and_sync(config, credentials).await?
+        }
+        ["forget", message] => forget(&config, message)?,
+        ["forget-event", event_id] => forget_event(&config, event_id)?,
+        ["import", format, path] => import(&config, format, Path::new(path))?,
+        ["export", path] => export(&config, Path::new(path))?,
+        ["reembed"] => reembed(&config)?,
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(1)
+        }
+    }
+
+    Ok(())
 }
@@ -3,24 +3,30 @@ use matrix_sdk::{
     deserialized_responses::RoomEvent,
     room::Joined,
     ruma::{
-        api::client::context::get_context::v3 as get_context,
-        events::{
-            room::message::OriginalSyncRoomMessageEvent,
-            AnySyncMessageLikeEvent,
-            AnySyncRoomEvent,
-            SyncMessageLikeEvent,
+        api::{
+            client::{
+                account::whoami::v3 as whoami,
+                context::get_context::v3 as get_context,
+                error::ErrorKind,
+                Error as ClientApiError,
+            },
+            error::{FromHttpResponseError, ServerError},
         },
+        events::{AnySyncMessageLikeEvent, AnySyncRoomEvent, SyncMessageLikeEvent},
+        EventId,
     },
     Client,
+    HttpError,
+    RumaApiError,
 };
 
 // The context API is missing from the Matrix SDK
 pub async fn get_events_before(
-    event: &OriginalSyncRoomMessageEvent,
+    event_id: &EventId,
     client: &Client,
     room: &Joined,
 ) -> Result<Vec<RoomEvent>, matrix_sdk::Error> {
-    let request = get_context::Request::new(room.room_id(), &event.event_id);
+    let request = get_context::Request::new(room.room_id(), event_id);
     let http_response = client.send(request, None).await?;
 
     let mut response = Vec::with_capacity(http_response.events_before.len());
@@ -42,3 +48,20 @@ pub async fn get_events_before(
 
     Ok(response)
 }
+
+// Used to check that a restored access token is still accepted
+pub async fn whoami(client: &Client) -> Result<whoami::Response, HttpError> {
+    client.send(whoami::Request::new(), None).await
+}
+
+pub fn is_unknown_token(error: &HttpError) -> bool {
+    matches!(
+        error,
+        HttpError::Api(FromHttpResponseError::Server(ServerError::Known(
+            RumaApiError::ClientApi(ClientApiError {
+                kind: ErrorKind::UnknownToken { .. },
+                ..
+            })
+        )))
+    )
+}
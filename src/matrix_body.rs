@@ -1,5 +1,6 @@
 extern crate matrix_sdk;
 use matrix_sdk::{
+    deserialized_responses::RoomEvent,
     room::Joined,
     ruma::{
         events::{
@@ -18,6 +19,8 @@ use matrix_sdk::{
             MessageLikeEvent,
         },
         serde::Raw,
+        EventId,
+        OwnedEventId,
     },
     Client,
 };
@@ -76,21 +79,29 @@ impl HasBody for TextMessageEventContent {
     }
 }
 
-impl HasBody for OriginalRoomMessageEvent {
+impl HasBody for RoomMessageEventContent {
     fn get_body(&self) -> Option<Body> {
-        match &self.content.msgtype {
+        // Edits only repeat the new content as a fallback
+        if let Some(Relation::Replacement(_)) = &self.relates_to {
+            return None;
+        }
+
+        match &self.msgtype {
             MessageType::Text(content) => content.get_body(),
             _ => None
         }
     }
 }
 
+impl HasBody for OriginalRoomMessageEvent {
+    fn get_body(&self) -> Option<Body> {
+        self.content.get_body()
+    }
+}
+
 impl HasBody for OriginalSyncRoomMessageEvent {
     fn get_body(&self) -> Option<Body> {
-        match &self.content.msgtype {
-            MessageType::Text(content) => content.get_body(),
-            _ => None
-        }
+        self.content.get_body()
     }
 }
 
@@ -130,27 +141,41 @@ impl HasBody for Raw<AnyRoomEvent> {
     }
 }
 
+fn get_body_and_event_id(event: &Raw<AnyRoomEvent>) -> Option<(Body, OwnedEventId)> {
+    let event = event.deserialize().ok()?;
+    let body = event.get_body()?;
+    Some((body, event.event_id().to_owned()))
+}
+
+// Events are given most recent first
+fn get_latest_body(events: &[RoomEvent]) -> Option<(Body, OwnedEventId)> {
+    events
+        .iter()
+        .find_map(|event| get_body_and_event_id(&event.event))
+}
+
+/// Find the message which the given event was responding to, along with its
+/// event ID. Events which are already known to have come before it, most
+/// recent first, are searched before asking the homeserver.
 pub async fn get_previous_body(
-    event: &OriginalSyncRoomMessageEvent,
+    event_id: &EventId,
+    content: &RoomMessageEventContent,
+    events_before: &[RoomEvent],
     client: &Client,
     room: &Joined,
-) -> Result<Option<Body>, matrix_sdk::Error> {
+) -> Result<Option<(Body, OwnedEventId)>, matrix_sdk::Error> {
     // Look for explicit replies first
-    if let Some(Relation::Reply { in_reply_to }) = &event.content.relates_to {
+    if let Some(Relation::Reply { in_reply_to }) = &content.relates_to {
         let previous_event = room.event(&in_reply_to.event_id).await?;
-        if let Some(previous_body) = previous_event.event.get_body() {
-            return Ok(Some(previous_body));
+        if let Some(previous) = get_body_and_event_id(&previous_event.event) {
+            return Ok(Some(previous));
         }
     }
 
     // Fall back to chronological order
-    let events_before = get_events_before(event, client, room).await?;
-    // We must check each event until we find one which is a text message
-    for previous_event in events_before.iter() {
-        if let Some(previous_body) = previous_event.event.get_body() {
-            return Ok(Some(previous_body));
-        }
+    if let Some(previous) = get_latest_body(events_before) {
+        return Ok(Some(previous));
     }
-
-    Ok(None)
+    let events_before = get_events_before(event_id, client, room).await?;
+    Ok(get_latest_body(&events_before))
 }
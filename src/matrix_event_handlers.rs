@@ -1,5 +1,3 @@
-extern crate dirs;
-
 extern crate futures;
 use futures::try_join;
 
@@ -8,9 +6,19 @@ use matrix_sdk::{
     config::SyncSettings,
     event_handler::Ctx,
     room::{Joined, Room},
-    ruma::events::room::{
-        member::StrippedRoomMemberEvent,
-        message::{OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+    ruma::{
+        events::room::{
+            member::{MembershipState, OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent},
+            message::{
+                OriginalSyncRoomMessageEvent,
+                Relation,
+                Replacement,
+                RoomMessageEventContent,
+            },
+            redaction::OriginalSyncRoomRedactionEvent,
+        },
+        OwnedRoomId,
+        RoomId,
     },
     Account,
     Client,
@@ -21,27 +29,221 @@ use matrix_sdk_sled::make_store_config;
 
 extern crate mime;
 
+extern crate rand;
+
 extern crate tokio;
 use tokio::time::{sleep, Duration};
 
-use std::fs::File;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
+};
 
 use crate::{
+    backfill::backfill,
+    config::{Behaviour, Config, Credentials, ReplyTo, RoomConfig},
+    matrix_api::{is_unknown_token, whoami},
     matrix_body::{get_previous_body, Body, HasBody},
-    store::ResponseStore,
+    room_settings::get_room_settings,
+    session::{load_session, save_session},
+    store::{BackfillProgress, Provenance, Response, ResponseStore},
 };
 
-async fn send_response(body: &Body, room: &Joined, database: &ResponseStore) -> anyhow::Result<()> {
-    if let Ok(response) = database.respond(&body.plain) {
+/// When Axyn last replied in each room, so that its replies can be spaced out.
+#[derive(Clone, Default)]
+struct LastReplies(Arc<Mutex<HashMap<OwnedRoomId, Instant>>>);
+
+/// A reply which is about to be sent, holding back any others in its room.
+struct ReplyClaim {
+    previous: Option<Instant>,
+    claimed: Instant,
+}
+
+impl LastReplies {
+    /// Record a reply in a room unless the room is cooling down, so that
+    /// messages arriving while it is sent aren't replied to as well.
+    fn claim(&self, room_id: &RoomId, cooldown: Duration) -> Option<ReplyClaim> {
+        let mut last_replies = self.0.lock().unwrap();
+        let previous = last_replies.get(room_id).copied();
+        if previous.map_or(false, |replied| replied.elapsed() < cooldown) {
+            return None;
+        }
+
+        let claimed = Instant::now();
+        last_replies.insert(room_id.to_owned(), claimed);
+        Some(ReplyClaim { previous, claimed })
+    }
+
+    /// Forget a reply which wasn't sent after all.
+    fn release(&self, room_id: &RoomId, claim: ReplyClaim) {
+        let mut last_replies = self.0.lock().unwrap();
+        if last_replies.get(room_id) != Some(&claim.claimed) {
+            return;
+        }
+
+        match claim.previous {
+            Some(previous) => last_replies.insert(room_id.to_owned(), previous),
+            None => last_replies.remove(room_id),
+        };
+    }
+}
+
+// Returns whether a response was sent
+async fn send_response(
+    body: &Body,
+    room: &Joined,
+    database: &ResponseStore,
+) -> anyhow::Result<bool> {
+    if let Ok(Response {
+        body: response,
+        distance,
+    }) = database.respond(&body.plain, Some(room.room_id().as_str()))
+    {
+        println!(
+            "Replying in {} to a message {:.3} from the closest prompt",
+            room.room_id(),
+            distance
+        );
+
         let response_content = match response.html {
             Some(html) => RoomMessageEventContent::text_html(response.plain, html),
             None => RoomMessageEventContent::text_plain(response.plain),
         };
 
         room.send(response_content, None).await?;
+        return Ok(true);
     }
 
-    Ok(())
+    Ok(false)
+}
+
+/// Whether a message mentions Axyn, or replies to one of its messages.
+async fn mentions_us(
+    body: &Body,
+    event: &OriginalSyncRoomMessageEvent,
+    client: &Client,
+    room: &Joined,
+) -> anyhow::Result<bool> {
+    let own_user_id = client.user_id().await.expect("Getting own user ID");
+
+    if let Some(Relation::Reply { in_reply_to }) = &event.content.relates_to {
+        let replied_to = room.event(&in_reply_to.event_id).await?;
+        if replied_to.event.deserialize()?.sender() == own_user_id {
+            return Ok(true);
+        }
+    }
+
+    // Mentions link to the user ID, but are shown as the display name
+    let user_id = own_user_id.as_str();
+    if body.plain.contains(user_id)
+        || body
+            .html
+            .as_deref()
+            .map_or(false, |html| html.contains(user_id))
+    {
+        return Ok(true);
+    }
+
+    let display_name = room
+        .get_member_no_sync(&own_user_id)
+        .await?
+        .and_then(|member| member.display_name().map(str::to_string));
+    Ok(display_name.map_or(false, |display_name| {
+        contains_name(&body.plain, &display_name)
+    }))
+}
+
+/// Whether some text contains a name as a whole word, ignoring case.
+fn contains_name(text: &str, name: &str) -> bool {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return false;
+    }
+
+    let text = text.to_lowercase();
+    text.match_indices(&name).any(|(start, _)| {
+        let end = start + name.len();
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        !before.map_or(false, char::is_alphanumeric) && !after.map_or(false, char::is_alphanumeric)
+    })
+}
+
+/// Whether to reply to a message, following the reply policy of the
+/// configuration, which takes precedence, or the room's settings. The room's
+/// cooldown is claimed for the reply if so.
+async fn should_reply(
+    body: &Body,
+    event: &OriginalSyncRoomMessageEvent,
+    client: &Client,
+    room: &Joined,
+    behaviour: &Behaviour,
+    rooms: &HashMap<String, RoomConfig>,
+    last_replies: &LastReplies,
+) -> anyhow::Result<Option<ReplyClaim>> {
+    let room_config = rooms.get(room.room_id().as_str());
+    let settings = get_room_settings(room).await?;
+
+    let reply_to = room_config
+        .and_then(|room_config| room_config.reply_to)
+        .or(settings.reply_to)
+        .unwrap_or(behaviour.reply_to);
+    let reply_probability = room_config
+        .and_then(|room_config| room_config.reply_probability)
+        .or(settings.reply_probability)
+        .unwrap_or(behaviour.reply_probability);
+    let reply_cooldown = room_config
+        .and_then(|room_config| room_config.reply_cooldown)
+        .or(settings.reply_cooldown)
+        .unwrap_or(behaviour.reply_cooldown);
+
+    let may_reply = match reply_to {
+        ReplyTo::All => true,
+        ReplyTo::Direct => room.is_direct(),
+        ReplyTo::Mentions => mentions_us(body, event, client, room).await?,
+    };
+    if !may_reply || rand::random::<f64>() >= reply_probability {
+        return Ok(None);
+    }
+
+    Ok(last_replies.claim(room.room_id(), Duration::from_secs(reply_cooldown)))
+}
+
+/// Whether a message was sent recently enough to be replied to.
+fn is_recent(event: &OriginalSyncRoomMessageEvent, max_age: Duration) -> bool {
+    let sent = match event.origin_server_ts.to_system_time() {
+        Some(sent) => sent,
+        None => return true,
+    };
+
+    // Clocks may disagree about messages which were only just sent
+    match SystemTime::now().duration_since(sent) {
+        Ok(age) => age <= max_age,
+        Err(_) => true,
+    }
+}
+
+/// Whether messages in a room may be learned from. Unless the configuration or
+/// the room's admins say otherwise, only public rooms without encryption are.
+async fn may_learn(
+    room: &Joined,
+    behaviour: &Behaviour,
+    rooms: &HashMap<String, RoomConfig>,
+) -> anyhow::Result<bool> {
+    let room_config = rooms.get(room.room_id().as_str());
+    if let Some(learn) = room_config.and_then(|room_config| room_config.learn) {
+        return Ok(learn);
+    }
+
+    if let Some(learn) = get_room_settings(room).await?.learn {
+        return Ok(learn);
+    }
+
+    Ok((behaviour.learn_from_private_rooms || room.is_public())
+        && (behaviour.learn_from_encrypted_rooms || !room.is_encrypted()))
 }
 
 async fn learn_from_message(
@@ -51,10 +253,38 @@ async fn learn_from_message(
     room: &Joined,
     database: &ResponseStore,
 ) -> anyhow::Result<()> {
-    let previous_body = get_previous_body(event, client, room).await?;
+    let previous = get_previous_body(&event.event_id, &event.content, &[], client, room).await?;
+
+    if let Some((previous_body, previous_event_id)) = previous {
+        let provenance = Provenance {
+            event_id: Some(event.event_id.to_string()),
+            prompt_event_id: Some(previous_event_id.to_string()),
+            room_id: Some(room.room_id().to_string()),
+            sender: Some(event.sender.to_string()),
+            timestamp: Some(event.origin_server_ts.0.into()),
+            prompt: None,
+            source: None,
+        };
+        database.insert(&previous_body.plain, body, provenance)?;
+    }
+
+    Ok(())
+}
+
+async fn learn_from_edit(
+    replacement: &Replacement,
+    event: &OriginalSyncRoomMessageEvent,
+    room: &Joined,
+    database: &ResponseStore,
+) -> anyhow::Result<()> {
+    // Only the original sender may edit a message
+    let original_event = room.event(&replacement.event_id).await?;
+    if original_event.event.deserialize()?.sender() != event.sender {
+        return Ok(());
+    }
 
-    if let Some(previous_body) = previous_body {
-        database.insert(&previous_body.plain, body)?;
+    if let Some(body) = replacement.new_content.get_body() {
+        database.replace_event(replacement.event_id.as_str(), &body)?;
     }
 
     Ok(())
@@ -65,6 +295,9 @@ async fn process_message(
     client: Client,
     room: Room,
     Ctx(database): Ctx<ResponseStore>,
+    Ctx(behaviour): Ctx<Behaviour>,
+    Ctx(rooms): Ctx<Arc<HashMap<String, RoomConfig>>>,
+    Ctx(last_replies): Ctx<LastReplies>,
 ) {
     // Don't respond to our own messages
     if event.sender == client.user_id().await.expect("Getting own user ID") {
@@ -72,23 +305,186 @@ async fn process_message(
     }
 
     if let Room::Joined(room) = room {
-        if let Some(body) = event.get_body() {
-            if let Err(error) = send_response(&body, &room, &database).await {
-                eprintln!("Error sending response: {}", error);
+        // Edits update what was learned from the original message, rather
+        // than being responded to as new messages
+        if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+            match may_learn(&room, &behaviour, &rooms).await {
+                Ok(true) => {
+                    if let Err(error) = learn_from_edit(replacement, &event, &room, &database).await
+                    {
+                        eprintln!("Error learning from edit: {}", error);
+                    }
+                }
+                Ok(false) => {}
+                Err(error) => eprintln!("Error checking whether to learn from edit: {}", error),
+            }
+        } else if let Some(body) = event.get_body() {
+            // Messages which were missed while offline are still learned from,
+            // but it's too late to reply to them
+            if is_recent(&event, Duration::from_secs(behaviour.max_reply_age)) {
+                let reply = should_reply(
+                    &body,
+                    &event,
+                    &client,
+                    &room,
+                    &behaviour,
+                    &rooms,
+                    &last_replies,
+                )
+                .await;
+                match reply {
+                    Ok(Some(claim)) => match send_response(&body, &room, &database).await {
+                        Ok(true) => {}
+                        Ok(false) => last_replies.release(room.room_id(), claim),
+                        Err(error) => {
+                            last_replies.release(room.room_id(), claim);
+                            eprintln!("Error sending response: {}", error);
+                        }
+                    },
+                    Ok(None) => {}
+                    Err(error) => eprintln!("Error checking whether to reply: {}", error),
+                }
+            }
+
+            match may_learn(&room, &behaviour, &rooms).await {
+                Ok(true) => {
+                    if let Err(error) =
+                        learn_from_message(body.clone(), &event, &client, &room, &database).await
+                    {
+                        eprintln!("Error learning from message: {}", error);
+                    }
+                }
+                Ok(false) => {}
+                Err(error) => eprintln!("Error checking whether to learn from message: {}", error),
+            }
+        }
+
+        if behaviour.read_receipts {
+            if let Err(error) = room.read_receipt(&event.event_id).await {
+                eprintln!("Error setting read receipt: {}", error);
+            }
+        }
+    }
+}
+
+// Forget anything learned from messages which have been redacted
+async fn process_redaction(
+    event: OriginalSyncRoomRedactionEvent,
+    Ctx(database): Ctx<ResponseStore>,
+) {
+    match database.remove_by_event(event.redacts.as_str()) {
+        Ok(0) => {}
+        Ok(removed) => println!(
+            "Forgot {} responses after {} was redacted",
+            removed, event.redacts
+        ),
+        Err(error) => eprintln!("Error forgetting redacted message: {}", error),
+    }
+}
+
+fn spawn_backfill(room: Joined, client: Client, database: ResponseStore, limit: usize) {
+    tokio::spawn(async move {
+        if let Err(error) = backfill(&room, &client, &database, limit).await {
+            eprintln!(
+                "Error learning from the history of {}: {}",
+                room.room_id(),
+                error
+            );
+        }
+    });
+}
+
+// Start learning from the history of rooms once they have been joined
+async fn backfill_on_join(
+    event: OriginalSyncRoomMemberEvent,
+    client: Client,
+    room: Room,
+    Ctx(database): Ctx<ResponseStore>,
+    Ctx(behaviour): Ctx<Behaviour>,
+    Ctx(rooms): Ctx<Arc<HashMap<String, RoomConfig>>>,
+) {
+    if behaviour.backfill_messages == 0 || event.content.membership != MembershipState::Join {
+        return;
+    }
+
+    if event.state_key != client.user_id().await.expect("Getting own user ID") {
+        return;
+    }
+
+    // Changes to the display name or avatar are sent as joins too
+    let previous = event.prev_content().map(|content| &content.membership);
+    if previous == Some(&MembershipState::Join) {
+        return;
+    }
+
+    if let Room::Joined(room) = room {
+        match may_learn(&room, &behaviour, &rooms).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(error) => {
+                eprintln!(
+                    "Error checking whether to learn from room history: {}",
+                    error
+                );
+                return;
             }
+        }
 
-            if let Err(error) = learn_from_message(body.clone(), &event, &client, &room, &database).await {
-                eprintln!("Error learning from message: {}", error);
+        // The history starts from the earliest event of the latest sync
+        let progress = BackfillProgress {
+            token: room.last_prev_batch(),
+            messages: 0,
+        };
+        match database.start_backfill(room.room_id().as_str(), &progress) {
+            Ok(true) => {
+                println!("Learning from the history of {}", room.room_id());
+                spawn_backfill(room, client, database, behaviour.backfill_messages);
             }
+            Ok(false) => {}
+            Err(error) => eprintln!("Error starting to learn from room history: {}", error),
         }
+    }
+}
 
-        if let Err(error) = room.read_receipt(&event.event_id).await {
-            eprintln!("Error setting read receipt: {}", error);
+// Continue learning from the history of rooms where that was interrupted
+async fn resume_backfills(
+    client: &Client,
+    database: &ResponseStore,
+    behaviour: &Behaviour,
+    rooms: &HashMap<String, RoomConfig>,
+) -> anyhow::Result<()> {
+    if behaviour.backfill_messages == 0 {
+        return Ok(());
+    }
+
+    for room in client.joined_rooms() {
+        let unfinished = database
+            .backfill_progress(room.room_id().as_str())?
+            .map_or(false, |progress| progress.token.is_some());
+
+        if unfinished && may_learn(&room, behaviour, rooms).await? {
+            spawn_backfill(
+                room,
+                client.clone(),
+                database.clone(),
+                behaviour.backfill_messages,
+            );
         }
     }
+
+    Ok(())
 }
 
-async fn join_on_invite(room_member: StrippedRoomMemberEvent, client: Client, room: Room) {
+async fn join_on_invite(
+    room_member: StrippedRoomMemberEvent,
+    client: Client,
+    room: Room,
+    Ctx(behaviour): Ctx<Behaviour>,
+) {
+    if !behaviour.join_on_invite {
+        return;
+    }
+
     // Only respond to invites for ourself
     if room_member.state_key != client.user_id().await.expect("Getting own user ID") {
         return;
@@ -119,19 +515,24 @@ async fn join_on_invite(room_member: StrippedRoomMemberEvent, client: Client, ro
     }
 }
 
-async fn set_display_name(account: &Account) -> anyhow::Result<()> {
-    if account.get_display_name().await? != Some("Axyn".to_string()) {
+async fn set_display_name(account: &Account, display_name: &str) -> anyhow::Result<()> {
+    if account.get_display_name().await?.as_deref() != Some(display_name) {
         println!("Setting display name");
-        account.set_display_name(Some("Axyn")).await?;
+        account.set_display_name(Some(display_name)).await?;
     }
 
     Ok(())
 }
 
-async fn set_avatar(account: &Account) -> anyhow::Result<()> {
-    if account.get_avatar_url().await? == None {
+async fn set_avatar(account: &Account, avatar_path: Option<&Path>) -> anyhow::Result<()> {
+    let avatar_path = match avatar_path {
+        Some(avatar_path) => avatar_path,
+        None => return Ok(()),
+    };
+
+    if account.get_avatar_url().await?.is_none() {
         println!("Setting avatar");
-        let mut image = File::open(env!("AVATAR_PNG"))?;
+        let mut image = File::open(avatar_path)?;
         account.upload_avatar(&mime::IMAGE_PNG, &mut image).await?;
     }
 
@@ -142,6 +543,10 @@ async fn sync(client: &Client) -> anyhow::Result<()> {
     client
         .register_event_handler(process_message)
         .await
+        .register_event_handler(process_redaction)
+        .await
+        .register_event_handler(backfill_on_join)
+        .await
         .register_event_handler(join_on_invite)
         .await;
 
@@ -151,31 +556,155 @@ async fn sync(client: &Client) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub async fn login_and_sync(
-    homeserver_url: String,
-    username: &str,
-    password: &str,
-    device_id: &str,
+async fn login_with_password(
+    client: &Client,
+    credentials: &Credentials,
+    device_id: Option<&str>,
+    session_path: &Path,
 ) -> anyhow::Result<()> {
-    let path = dirs::home_dir().expect("Finding home directory");
-    let store_config = make_store_config(path, None)?;
+    println!("Logging in with password");
+    client
+        .login(
+            &credentials.username,
+            &credentials.password,
+            device_id,
+            Some("Axyn"),
+        )
+        .await?;
+
+    let session = client.session().await.expect("Getting new session");
+    save_session(session_path, &session)?;
+
+    Ok(())
+}
+
+async fn login(client: &Client, config: &Config, credentials: &Credentials) -> anyhow::Result<()> {
+    let session_path = config.data_directory.session();
+
+    match load_session(&session_path)? {
+        Some(session) => {
+            println!("Restoring previous session");
+            let device_id = session.device_id.clone();
+            client.restore_login(session).await?;
+
+            match whoami(client).await {
+                Ok(_) => Ok(()),
+                Err(error) if is_unknown_token(&error) => {
+                    eprintln!("Previous session was rejected by the homeserver");
+                    // Keep the same device so that our encryption keys stay valid
+                    login_with_password(
+                        client,
+                        credentials,
+                        Some(device_id.as_str()),
+                        &session_path,
+                    )
+                    .await
+                }
+                Err(error) => Err(error.into()),
+            }
+        }
+        None => {
+            login_with_password(
+                client,
+                credentials,
+                config.device_id.as_deref(),
+                &session_path,
+            )
+            .await
+        }
+    }
+}
+
+pub async fn login_and_sync(config: Config, credentials: Credentials) -> anyhow::Result<()> {
+    config.data_directory.create()?;
+    let store_config = make_store_config(config.data_directory.matrix_store(), None)?;
 
     let client = Client::builder()
-        .homeserver_url(homeserver_url)
+        .homeserver_url(&credentials.homeserver_url)
         .store_config(store_config)
         .build()
         .await?;
 
-    let database = ResponseStore::load()?;
-    client.register_event_handler_context(database);
+    let database = ResponseStore::load(&config)?;
+    client.register_event_handler_context(database.clone());
+    client.register_event_handler_context(config.behaviour.clone());
+    client.register_event_handler_context(Arc::new(config.rooms.clone()));
+    client.register_event_handler_context(LastReplies::default());
 
-    client
-        .login(username, password, Some(device_id), Some("Axyn"))
-        .await?;
-    println!("Connected to Matrix as {}", username);
+    login(&client, &config, &credentials).await?;
+    println!("Connected to Matrix as {}", credentials.username);
+
+    resume_backfills(&client, &database, &config.behaviour, &config.rooms).await?;
 
     let account = &client.account();
-    try_join!(set_display_name(account), set_avatar(account), sync(&client))?;
+    try_join!(
+        set_display_name(account, &config.display_name),
+        set_avatar(account, config.avatar_path.as_deref()),
+        sync(&client)
+    )?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_name_as_a_whole_word() {
+        assert!(contains_name("hi ax!", "Ax"));
+        assert!(contains_name("Axyn, how are you?", "axyn"));
+        assert!(contains_name("ask Axyn", " Axyn "));
+        assert!(!contains_name("relax", "Ax"));
+        assert!(!contains_name("axyns", "Axyn"));
+        assert!(!contains_name("relax, ax", ""));
+        assert!(!contains_name("relax, ax", "  "));
+    }
+
+    #[test]
+    fn claims_block_replies_during_the_cooldown() {
+        let last_replies = LastReplies::default();
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let other_room_id = RoomId::parse("!other:example.org").unwrap();
+        let cooldown = Duration::from_secs(60);
+
+        assert!(last_replies.claim(&room_id, cooldown).is_some());
+        assert!(last_replies.claim(&room_id, cooldown).is_none());
+        assert!(last_replies.claim(&other_room_id, cooldown).is_some());
+        assert!(last_replies.claim(&room_id, Duration::ZERO).is_some());
+    }
+
+    #[test]
+    fn released_claims_no_longer_block_replies() {
+        let last_replies = LastReplies::default();
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let cooldown = Duration::from_secs(60);
+
+        let claim = last_replies.claim(&room_id, cooldown).unwrap();
+        last_replies.release(&room_id, claim);
+        let claim = last_replies.claim(&room_id, cooldown).unwrap();
+        assert!(last_replies.claim(&room_id, cooldown).is_none());
+
+        // An earlier reply is kept when a later one is released
+        last_replies.release(&room_id, claim);
+        let earlier = last_replies.claim(&room_id, Duration::ZERO).unwrap();
+        let later = last_replies.claim(&room_id, Duration::ZERO).unwrap();
+        last_replies.release(&room_id, later);
+        assert!(last_replies.claim(&room_id, cooldown).is_none());
+        last_replies.release(&room_id, earlier);
+        assert!(last_replies.claim(&room_id, cooldown).is_some());
+    }
+
+    #[test]
+    fn replaced_claims_are_not_released() {
+        let last_replies = LastReplies::default();
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+
+        let earlier = last_replies.claim(&room_id, Duration::ZERO).unwrap();
+        let _later = last_replies.claim(&room_id, Duration::ZERO).unwrap();
+        last_replies.release(&room_id, earlier);
+        assert!(last_replies
+            .claim(&room_id, Duration::from_secs(60))
+            .is_none());
+    }
+}
@@ -0,0 +1,60 @@
+extern crate matrix_sdk;
+use matrix_sdk::{room::Joined, ruma::events::StateEventType};
+
+extern crate serde;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+
+extern crate serde_json;
+
+use crate::config::{is_probability, ReplyTo};
+
+/// The type of the state event which room admins can send to configure Axyn,
+/// with an empty state key.
+pub const ROOM_SETTINGS_EVENT_TYPE: &str = "org.axyn.settings";
+
+/// Settings chosen by the admins of a room. Fields which are invalid are
+/// ignored, rather than the whole event.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// Whether Axyn may learn from messages sent in the room.
+    #[serde(deserialize_with = "ignore_invalid")]
+    pub learn: Option<bool>,
+    /// Which messages Axyn replies to, and how often, as in the `behaviour`
+    /// section of the configuration.
+    #[serde(deserialize_with = "ignore_invalid")]
+    pub reply_to: Option<ReplyTo>,
+    #[serde(deserialize_with = "ignore_invalid")]
+    pub reply_probability: Option<f64>,
+    #[serde(deserialize_with = "ignore_invalid")]
+    pub reply_cooldown: Option<u64>,
+}
+
+fn ignore_invalid<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
+
+#[derive(Deserialize)]
+struct RoomSettingsEvent {
+    content: RoomSettings,
+}
+
+pub async fn get_room_settings(room: &Joined) -> Result<RoomSettings, matrix_sdk::Error> {
+    let event_type = StateEventType::from(ROOM_SETTINGS_EVENT_TYPE);
+
+    let mut settings = match room.get_state_event(event_type, "").await? {
+        Some(event) => match event.deserialize_as::<RoomSettingsEvent>() {
+            Ok(event) => event.content,
+            Err(_) => RoomSettings::default(),
+        },
+        None => RoomSettings::default(),
+    };
+
+    settings.reply_probability = settings.reply_probability.filter(|&p| is_probability(p));
+    Ok(settings)
+}
@@ -0,0 +1,48 @@
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::Path,
+};
+
+extern crate matrix_sdk;
+use matrix_sdk::Session;
+
+extern crate serde_cbor;
+
+pub fn load_session(path: &Path) -> anyhow::Result<Option<Session>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    // Logging in with the password replaces a session which can't be read
+    match serde_cbor::from_reader(file) {
+        Ok(session) => Ok(Some(session)),
+        Err(error) => {
+            eprintln!("Ignoring unreadable session: {}", error);
+            Ok(None)
+        }
+    }
+}
+
+pub fn save_session(path: &Path, session: &Session) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    // The access token is as good as a password, so keep it private
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    // Written to a separate file first, so that the previous session is kept
+    // if Axyn stops part way through
+    let temporary_path = path.with_extension("new");
+    let file = options.open(&temporary_path)?;
+    serde_cbor::to_writer(&file, session)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
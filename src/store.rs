@@ -1,9 +1,18 @@
 use std::{
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    slice,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    thread,
 };
 
-extern crate dirs;
-
 extern crate hnsw;
 use hnsw::{Hnsw, Searcher};
 extern crate space;
@@ -12,16 +21,34 @@ use space::{Metric, Neighbor};
 extern crate quick_error;
 use quick_error::quick_error;
 
+extern crate serde;
+use serde::{Deserialize, Serialize};
+
 extern crate sled;
-use sled::Db;
+use sled::{Batch, CompareAndSwapError, Db, Tree};
 extern crate serde_cbor;
 
 extern crate rand;
-use rand::{rngs::StdRng, seq::SliceRandom};
+use rand::seq::SliceRandom;
+extern crate rand_pcg;
+use rand_pcg::Pcg32;
 
 use crate::{
+    config::{Config, EmbeddingConfig, Privacy, ResponsesConfig, RoomConfig},
     matrix_body::Body,
-    vectors::{load_vectors, utterance_to_vector, Vector, VectorLoadError, Vectors},
+    tokenizer::tokenize,
+    vectors::{
+        first_principal_component,
+        load_vectors,
+        normalize_vector,
+        remove_component,
+        sif_weight,
+        tokens_to_vector,
+        Vector,
+        VectorLoadError,
+        Vectors,
+        Weighting,
+    },
 };
 
 quick_error! {
@@ -37,6 +64,11 @@ quick_error! {
             source(error)
             display("(de)serialization error: {}", error)
         }
+        IOError(error: io::Error) {
+            from(error: io::Error) -> (error)
+            source(error)
+            display("I/O error: {}", error)
+        }
         VectorLoadError(error: VectorLoadError) {
             from(error: VectorLoadError) -> (error)
             source(error)
@@ -51,116 +83,1714 @@ quick_error! {
         NoPromptVector {
             display("the prompt did not contain any words with known vectors")
         }
+        NoGoodMatch(distance: f64) {
+            display("the closest prompt is too distant ({})", distance)
+        }
+        OutdatedSchema(version: u64) {
+            display("the database is from an older version of Axyn (schema version {}), start Axyn once to upgrade it", version)
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-struct Euclidean;
-impl Metric<Vector> for Euclidean {
+/// How the distance between two prompts is measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Distance {
+    Euclidean,
+    /// One minus the cosine similarity. Prompt vectors are normalised when
+    /// this is used, so that only their directions are compared.
+    Cosine,
+}
+
+impl Default for Distance {
+    fn default() -> Self {
+        Distance::Euclidean
+    }
+}
+impl Metric<Vector> for Distance {
     type Unit = u64;
     fn distance(&self, a: &Vector, b: &Vector) -> u64 {
-        a.iter()
-            .zip(b.iter())
-            .map(|(&a, &b)| (a - b).powi(2))
-            .sum::<f64>()
-            .sqrt()
-            .to_bits()
+        let distance = match self {
+            Distance::Euclidean => a
+                .iter()
+                .zip(b.iter())
+                .map(|(&a, &b)| (a - b).powi(2))
+                .sum::<f64>()
+                .sqrt(),
+            Distance::Cosine => {
+                let similarity: f64 = a.iter().zip(b.iter()).map(|(&a, &b)| a * b).sum();
+                // Rounding can make the similarity of unit vectors slightly
+                // greater than one, and negative floats don't sort as bits
+                (1.0 - similarity).max(0.0)
+            }
+        };
+        distance.to_bits()
+    }
+}
+
+// The random number generator must be serializable to save the index, and
+// CBOR can only hold integers of up to 64 bits
+type Hnsw64 = Hnsw<Distance, Vector, Pcg32, 12, 24>;
+
+/// The vectors of prompts, along with the ID of the prompt of each, in the
+/// order they were inserted.
+#[derive(Serialize, Deserialize)]
+struct Index {
+    hnsw: Hnsw64,
+    prompt_ids: Vec<u64>,
+}
+impl Index {
+    fn new(distance: Distance) -> Self {
+        Index {
+            hnsw: Hnsw::new(distance),
+            prompt_ids: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, prompt_id: u64, vector: Vector, searcher: &mut Searcher<u64>) {
+        self.hnsw.insert(vector, searcher);
+        self.prompt_ids.push(prompt_id);
+    }
+
+    fn len(&self) -> usize {
+        self.prompt_ids.len()
+    }
+}
+
+const TOTAL_WORDS_KEY: &str = "total_words";
+/// The version of how words are counted, so that they can be counted again if
+/// it changes.
+const WORD_COUNTS_VERSION_KEY: &str = "word_counts_version";
+const WORD_COUNTS_VERSION: u64 = 2;
+/// The direction which was removed from the vectors of prompts, if any.
+const COMMON_COMPONENT_KEY: &str = "common_component";
+const DISTANCE_KEY: &str = "distance";
+/// What the vectors of prompts were worked out with, as an [`EmbeddedWith`].
+const EMBEDDED_WITH_KEY: &str = "embedded_with";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// The version of the format of the responses in the database.
+const SCHEMA_VERSION: u64 = 3;
+
+/// Incremented whenever the set of prompts changes, so that a saved index can
+/// be matched to the state of the database.
+const GENERATION_KEY: &str = "generation";
+/// The generation from which every added prompt is recorded in the index log.
+const INDEX_LOG_START_KEY: &str = "index_log_start";
+/// Which of [`PROMPT_VECTORS_TREES`] holds the current vectors of prompts.
+const PROMPT_VECTORS_TREE_KEY: &str = "prompt_vectors_tree";
+
+/// Prompts are embedded again into whichever of these trees isn't in use, so
+/// that the previous vectors are kept until every prompt has a new one.
+const PROMPT_VECTORS_TREES: [&str; 2] = ["prompt_vectors", "reembedded_prompt_vectors"];
+
+const INDEX_FILE_VERSION: u32 = 2;
+/// How many prompts are learned between saves of the index.
+const INDEX_SAVE_INTERVAL: u64 = 64;
+/// How many more neighbours to search for at first when some rooms are
+/// private, since their responses are skipped in other rooms.
+const PRIVATE_SEARCH_FACTOR: usize = 8;
+/// The index is rebuilt at startup when more than one in this many of its
+/// prompts have been removed.
+const REMOVED_PROMPTS_REBUILD_FRACTION: usize = 10;
+
+/// Identifies the word vectors and weighting which the vectors of prompts were
+/// worked out with, so that they are embedded again when either changes.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct EmbeddedWith {
+    dimensionality: usize,
+    words: usize,
+    weighting: Weighting,
+    /// Only used by SIF weighting.
+    smoothing: Option<f64>,
+}
+
+impl EmbeddedWith {
+    fn new(vectors: &Vectors, embedding: &EmbeddingConfig) -> Self {
+        EmbeddedWith {
+            dimensionality: vectors.dimensionality(),
+            words: vectors.len(),
+            weighting: embedding.weighting,
+            smoothing: match embedding.weighting {
+                Weighting::Mean => None,
+                Weighting::Sif => Some(embedding.smoothing),
+            },
+        }
+    }
+}
+
+/// Where a response was learned from. Everything is optional, since
+/// responses learned before this was recorded have none of it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Provenance {
+    /// The event which the response was learned from.
+    pub event_id: Option<String>,
+    /// The event which the response was a response to.
+    pub prompt_event_id: Option<String>,
+    pub room_id: Option<String>,
+    pub sender: Option<String>,
+    /// When the response was sent, in milliseconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+    /// The text of the prompt, as it was when the response was learned.
+    pub prompt: Option<String>,
+    /// Where in a corpus the response was imported from, so that it isn't
+    /// imported again.
+    pub source: Option<String>,
+}
+
+/// A response as it is kept in the database.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredResponse {
+    body: Body,
+    #[serde(default)]
+    provenance: Provenance,
+}
+
+/// A prompt and its responses, as kept in the database. Its vector is kept
+/// separately, since it can be recalculated from the text.
+#[derive(Serialize, Deserialize)]
+struct StoredPrompt {
+    /// Missing for prompts learned before their text was recorded, which
+    /// can't be embedded again.
+    text: Option<String>,
+    responses: Vec<StoredResponse>,
+}
+
+/// Responses as they were kept before provenance was recorded, with the
+/// fields of the body alongside the event IDs.
+#[derive(Deserialize)]
+struct LegacyResponse {
+    #[serde(flatten)]
+    body: Body,
+    #[serde(default)]
+    event_id: Option<String>,
+    #[serde(default)]
+    prompt_event_id: Option<String>,
+}
+
+impl StoredResponse {
+    fn event_ids(&self) -> impl Iterator<Item = &str> {
+        self.provenance
+            .event_id
+            .iter()
+            .chain(&self.provenance.prompt_event_id)
+            .map(String::as_str)
+    }
+}
+
+fn event_prefix(event_id: &str) -> Vec<u8> {
+    let mut prefix = event_id.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// The key linking an event to a prompt it was learned with. Event IDs can't
+/// contain NUL, so it separates the event ID from the prompt ID.
+fn event_key(event_id: &str, prompt_id: u64) -> Vec<u8> {
+    let mut key = event_prefix(event_id);
+    key.extend_from_slice(&prompt_id.to_be_bytes());
+    key
+}
+
+fn link_events(
+    events: &Tree,
+    prompt_id: u64,
+    responses: &[StoredResponse],
+) -> Result<(), StoreError> {
+    for event_id in responses.iter().flat_map(StoredResponse::event_ids) {
+        events.insert(event_key(event_id, prompt_id), &[] as &[u8])?;
+    }
+    Ok(())
+}
+
+/// Add responses to a prompt, creating it if it doesn't exist.
+fn push_responses(
+    prompts: &Tree,
+    prompt_id: u64,
+    text: Option<&str>,
+    responses: &[StoredResponse],
+) -> Result<(), StoreError> {
+    prompts.update_and_fetch(prompt_id.to_be_bytes(), |serialized_prompt| {
+        let mut prompt = match serialized_prompt {
+            Some(p) => serde_cbor::from_slice::<StoredPrompt>(p).expect("Deserializing prompt"),
+            None => StoredPrompt {
+                text: text.map(str::to_string),
+                responses: Vec::new(),
+            },
+        };
+
+        prompt.responses.extend_from_slice(responses);
+
+        Some(serde_cbor::to_vec(&prompt).expect("Serializing prompt"))
+    })?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile<I> {
+    version: u32,
+    generation: u64,
+    distance: Distance,
+    index: I,
+}
+
+/// The common component is only estimated once there are enough prompts for
+/// it to be meaningful, since it is fixed from then on.
+const COMMON_COMPONENT_MINIMUM_PROMPTS: usize = 100;
+
+fn read_count(value: Option<&[u8]>) -> u64 {
+    value
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0)
+}
+
+fn read_id(bytes: &[u8]) -> u64 {
+    read_count(Some(bytes))
+}
+
+fn increment_count(tree: &Tree, key: &[u8], amount: u64) -> Result<u64, StoreError> {
+    let count = tree.update_and_fetch(key, |count| {
+        Some((read_count(count) + amount).to_be_bytes().to_vec())
+    })?;
+    Ok(read_count(count.as_deref()))
+}
+
+fn decrement_count(tree: &Tree, key: &[u8], amount: u64) -> Result<(), StoreError> {
+    tree.update_and_fetch(key, |count| {
+        match read_count(count).saturating_sub(amount) {
+            0 => None,
+            count => Some(count.to_be_bytes().to_vec()),
+        }
+    })?;
+    Ok(())
+}
+
+/// Record the words of a prompt once for each of its learned responses, so
+/// that their frequencies are known.
+fn count_words(
+    word_counts: &Tree,
+    metadata: &Tree,
+    prompt: &str,
+    responses: u64,
+) -> Result<(), StoreError> {
+    let tokens = tokenize(prompt);
+
+    for token in &tokens {
+        increment_count(word_counts, token.to_lowercase().as_bytes(), responses)?;
+    }
+    increment_count(
+        metadata,
+        TOTAL_WORDS_KEY.as_bytes(),
+        tokens.len() as u64 * responses,
+    )?;
+
+    Ok(())
+}
+
+/// Forget the words of a prompt once for each of its removed responses.
+fn uncount_words(
+    word_counts: &Tree,
+    metadata: &Tree,
+    prompt: &str,
+    responses: u64,
+) -> Result<(), StoreError> {
+    let tokens = tokenize(prompt);
+
+    for token in &tokens {
+        decrement_count(word_counts, token.to_lowercase().as_bytes(), responses)?;
+    }
+    decrement_count(
+        metadata,
+        TOTAL_WORDS_KEY.as_bytes(),
+        tokens.len() as u64 * responses,
+    )?;
+
+    Ok(())
+}
+
+/// Forget the prompts which are already in a saved index.
+fn prune_index_log(index_log: &Tree, generation: u64) -> Result<(), StoreError> {
+    for pair in index_log.range(..=generation.to_be_bytes()) {
+        let (key, _) = pair?;
+        index_log.remove(key)?;
     }
+    Ok(())
+}
+
+/// Start a new generation which no saved index or logged prompt belongs to,
+/// since they no longer match the database.
+fn reset_index_log(metadata: &Tree, index_log: &Tree) -> Result<u64, StoreError> {
+    let generation = increment_count(metadata, GENERATION_KEY.as_bytes(), 1)?;
+    metadata.insert(INDEX_LOG_START_KEY, &generation.to_be_bytes()[..])?;
+    index_log.clear()?;
+    Ok(generation)
+}
+
+fn build_index(prompt_vectors: &Tree, distance: Distance) -> Result<Index, StoreError> {
+    let mut index = Index::new(distance);
+    let mut searcher = Searcher::default();
+
+    for pair in prompt_vectors.iter() {
+        let (prompt_id, serialized_vector) = pair?;
+        let vector = serde_cbor::from_slice(&serialized_vector)?;
+        index.insert(read_id(&prompt_id), vector, &mut searcher);
+    }
+
+    Ok(index)
+}
+
+fn read_index(path: &Path, distance: Distance) -> Result<Option<IndexFile<Index>>, StoreError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    // A file which can't be read is rebuilt rather than treated as an error
+    let index: IndexFile<Index> = match serde_cbor::from_reader(BufReader::new(file)) {
+        Ok(index) => index,
+        Err(error) => {
+            eprintln!("Ignoring unreadable index: {}", error);
+            return Ok(None);
+        }
+    };
+
+    if index.version != INDEX_FILE_VERSION || index.distance != distance {
+        return Ok(None);
+    }
+
+    Ok(Some(index))
+}
+
+fn write_index(
+    path: &Path,
+    index: &Index,
+    distance: Distance,
+    generation: u64,
+) -> Result<(), StoreError> {
+    let temporary_path = path.with_extension("tmp");
+
+    let mut file = BufWriter::new(File::create(&temporary_path)?);
+    let index = IndexFile {
+        version: INDEX_FILE_VERSION,
+        generation,
+        distance,
+        index,
+    };
+    serde_cbor::to_writer(&mut file, &index)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+
+    fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+/// Load the saved index if it can be brought up to date with the database,
+/// adding any prompts learned since it was saved. Returns whether the index
+/// needs saving.
+fn load_index(
+    path: &Path,
+    prompt_vectors: &Tree,
+    metadata: &Tree,
+    index_log: &Tree,
+    distance: Distance,
+) -> Result<(Index, bool), StoreError> {
+    let generation = read_count(metadata.get(GENERATION_KEY)?.as_deref());
+    let log_start = read_count(metadata.get(INDEX_LOG_START_KEY)?.as_deref());
+
+    match read_index(path, distance)? {
+        Some(saved) if saved.generation == generation => Ok((saved.index, false)),
+        Some(saved) if saved.generation >= log_start && saved.generation < generation => {
+            println!(
+                "Adding {} prompts to the saved HNSW",
+                generation - saved.generation
+            );
+
+            let mut index = saved.index;
+            let mut searcher = Searcher::default();
+            for pair in index_log.range((saved.generation + 1).to_be_bytes()..) {
+                let (_, prompt_id) = pair?;
+                // Prompts which were removed since don't need to be searched
+                if let Some(serialized_vector) = prompt_vectors.get(&prompt_id)? {
+                    let vector = serde_cbor::from_slice(&serialized_vector)?;
+                    index.insert(read_id(&prompt_id), vector, &mut searcher);
+                }
+            }
+
+            Ok((index, true))
+        }
+        _ => {
+            println!("Building HNSW");
+            Ok((build_index(prompt_vectors, distance)?, true))
+        }
+    }
+}
+
+fn schema_version(database: &Db, metadata: &Tree) -> Result<u64, StoreError> {
+    match metadata.get(SCHEMA_VERSION_KEY)? {
+        Some(version) => Ok(read_count(Some(&version[..]))),
+        // Version 1 had no metadata, but an empty database needs no migration
+        None if database.is_empty() => Ok(SCHEMA_VERSION),
+        None => Ok(1),
+    }
+}
+
+/// Reads learned responses without loading the vectors or changing the
+/// database, unlike [`ResponseStore::load`], which may migrate it or embed its
+/// prompts again.
+pub struct ResponseReader {
+    prompts: Tree,
+}
+
+impl ResponseReader {
+    pub fn open(config: &Config) -> Result<Self, StoreError> {
+        let database = sled::open(config.data_directory.responses())?;
+        let metadata = database.open_tree("metadata")?;
+
+        let version = schema_version(&database, &metadata)?;
+        if version != SCHEMA_VERSION {
+            return Err(StoreError::OutdatedSchema(version));
+        }
+
+        Ok(ResponseReader {
+            prompts: database.open_tree("prompts")?,
+        })
+    }
+
+    /// Every learned response, along with the text of its prompt, if known,
+    /// and where it came from.
+    pub fn responses(
+        &self,
+    ) -> impl Iterator<Item = Result<(Option<String>, Body, Provenance), StoreError>> + '_ {
+        self.prompts.iter().flat_map(|pair| {
+            let prompt = pair
+                .map_err(StoreError::from)
+                .and_then(|(_, serialized_prompt)| {
+                    let prompt: StoredPrompt = serde_cbor::from_slice(&serialized_prompt)?;
+                    Ok(prompt)
+                });
+
+            match prompt {
+                Ok(prompt) => {
+                    let text = prompt.text;
+                    prompt
+                        .responses
+                        .into_iter()
+                        .map(|response| Ok((text.clone(), response.body, response.provenance)))
+                        .collect()
+                }
+                Err(error) => vec![Err(error)],
+            }
+        })
+    }
+}
+
+/// How far back the history of a room has been learned from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BackfillProgress {
+    /// Where to continue reading the history from, or none once finished.
+    pub token: Option<String>,
+    /// How many messages have been learned from so far.
+    pub messages: usize,
+}
+
+/// A learned response, along with how far its prompt was from the message
+/// being responded to.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub body: Body,
+    pub distance: f64,
+}
+
+/// A response which may be chosen, along with how far its prompt was from the
+/// message and how likely it is to be chosen.
+struct Candidate {
+    body: Body,
+    distance: f64,
+    weight: f64,
 }
 
 #[derive(Clone)]
 pub struct ResponseStore {
     vectors: Arc<Vectors>,
+    embedding: EmbeddingConfig,
+    responses: ResponsesConfig,
+    rooms: Arc<HashMap<String, RoomConfig>>,
+    has_private_rooms: bool,
+    common_component: Option<Arc<Vector>>,
     database: Db,
-    hnsw_lock: Arc<RwLock<Hnsw<Euclidean, Vector, StdRng, 12, 24>>>,
+    word_counts: Tree,
+    metadata: Tree,
+    /// Prompts and their responses, keyed by an ID which stays the same
+    /// however the prompt is embedded.
+    prompts: Tree,
+    /// Looks up the ID of a prompt from its text.
+    prompt_ids: Tree,
+    /// The vector of each prompt, which is derived from its text.
+    prompt_vectors: Tree,
+    index_log: Tree,
+    index_path: PathBuf,
+    /// The progress of learning from the history of each room, by room ID.
+    backfill: Tree,
+    /// The sources of every response imported from a corpus.
+    imported_sources: Tree,
+    /// Links the IDs of the events which responses and prompts were learned
+    /// from to the IDs of their prompts.
+    events: Tree,
+    /// The number of prompts in the index whose responses have all been
+    /// removed, since HNSW doesn't support deletion.
+    removed_prompts: Arc<AtomicUsize>,
+    index_lock: Arc<RwLock<Index>>,
     searcher_lock: Arc<RwLock<Searcher<u64>>>,
+    /// Held while the index is being written, along with the generation of
+    /// the last index written, since saves can finish out of order.
+    saved_generation: Arc<Mutex<u64>>,
 }
 impl ResponseStore {
-    pub fn load() -> Result<Self, StoreError> {
-        let vectors = load_vectors()?;
-        let vectors_arc = Arc::new(vectors);
+    pub fn load(config: &Config) -> Result<Self, StoreError> {
+        // The vectors are cached in the data directory
+        config.data_directory.create()?;
+        let vectors = load_vectors(&config.vectors)?;
 
         println!("Opening database");
-        let mut path = dirs::home_dir().expect("Finding home directory");
-        path.push("responses");
-        let database = sled::open(path)?;
+        let database = sled::open(config.data_directory.responses())?;
+        ResponseStore::from_database(config, vectors, database)
+    }
+
+    /// Use a database which has been opened, migrating it and embedding its
+    /// prompts again if needed.
+    fn from_database(config: &Config, vectors: Vectors, database: Db) -> Result<Self, StoreError> {
+        let distance = config.embedding.distance;
+        let metadata = database.open_tree("metadata")?;
+        let prompt_vectors_tree =
+            read_count(metadata.get(PROMPT_VECTORS_TREE_KEY)?.as_deref()) as usize;
+        let mut store = ResponseStore {
+            vectors: Arc::new(vectors),
+            embedding: config.embedding.clone(),
+            responses: config.responses.clone(),
+            rooms: Arc::new(config.rooms.clone()),
+            has_private_rooms: config.responses.privacy == Privacy::Private
+                || config
+                    .rooms
+                    .values()
+                    .any(|room| room.privacy == Some(Privacy::Private)),
+            common_component: None,
+            word_counts: database.open_tree("word_counts")?,
+            metadata,
+            prompts: database.open_tree("prompts")?,
+            prompt_ids: database.open_tree("prompt_ids")?,
+            prompt_vectors: database.open_tree(PROMPT_VECTORS_TREES[prompt_vectors_tree])?,
+            index_log: database.open_tree("index_log")?,
+            index_path: config.data_directory.index(),
+            backfill: database.open_tree("backfill")?,
+            imported_sources: database.open_tree("imported_sources")?,
+            events: database.open_tree("events")?,
+            database,
+            removed_prompts: Arc::new(AtomicUsize::new(0)),
+            index_lock: Arc::new(RwLock::new(Index::new(distance))),
+            searcher_lock: Arc::new(RwLock::new(Searcher::default())),
+            saved_generation: Arc::new(Mutex::new(0)),
+        };
+
+        store.migrate()?;
+
+        // Words used to be counted from responses as well as prompts, and
+        // weren't forgotten along with them
+        let word_counts_version =
+            read_count(store.metadata.get(WORD_COUNTS_VERSION_KEY)?.as_deref());
+        if word_counts_version != WORD_COUNTS_VERSION {
+            println!("Counting words in existing prompts");
+            store.word_counts.clear()?;
+            store.metadata.remove(TOTAL_WORDS_KEY)?;
+
+            for pair in store.prompts.iter() {
+                let (_, serialized_prompt) = pair?;
+                let prompt: StoredPrompt = serde_cbor::from_slice(&serialized_prompt)?;
+                if let Some(text) = &prompt.text {
+                    let responses = prompt.responses.len() as u64;
+                    count_words(&store.word_counts, &store.metadata, text, responses)?;
+                }
+            }
+
+            store.metadata.insert(
+                WORD_COUNTS_VERSION_KEY,
+                &WORD_COUNTS_VERSION.to_be_bytes()[..],
+            )?;
+        }
+
+        // Databases from before the distance was configurable used Euclidean
+        let previous_distance = match store.metadata.get(DISTANCE_KEY)? {
+            Some(serialized_distance) => serde_cbor::from_slice(&serialized_distance)?,
+            None => Distance::Euclidean,
+        };
+        let distance_changed = distance != previous_distance;
+
+        // Databases from before this was recorded are embedded again in case
+        // the vectors have changed since
+        let embedded_with = EmbeddedWith::new(&store.vectors, &store.embedding);
+        let previous_embedded_with: Option<EmbeddedWith> =
+            match store.metadata.get(EMBEDDED_WITH_KEY)? {
+                Some(serialized) => Some(serde_cbor::from_slice(&serialized)?),
+                None => None,
+            };
+        let vectors_changed = previous_embedded_with.as_ref() != Some(&embedded_with)
+            && !store.prompt_vectors.is_empty();
+
+        // Written along with the vectors of prompts, once they have all been
+        // embedded again
+        let mut markers = Batch::default();
+        markers.insert(DISTANCE_KEY, serde_cbor::to_vec(&distance)?);
+        markers.insert(EMBEDDED_WITH_KEY, serde_cbor::to_vec(&embedded_with)?);
+        let mut new_component = None;
+        let mut component_dropped = false;
+        // The component of previous word vectors doesn't apply to new ones, so
+        // it is estimated again at the next start
+        if config.embedding.remove_common_component && !vectors_changed {
+            if let Some(serialized_component) = store.metadata.get(COMMON_COMPONENT_KEY)? {
+                store.common_component =
+                    Some(Arc::new(serde_cbor::from_slice(&serialized_component)?));
+            } else if store.prompt_vectors.len() >= COMMON_COMPONENT_MINIMUM_PROMPTS {
+                let mut features = Vec::new();
+                for pair in store.prompt_vectors.iter() {
+                    let (_, serialized_vector) = pair?;
+                    features.push(serde_cbor::from_slice::<Vector>(&serialized_vector)?);
+                }
+
+                let features: Vec<&Vector> = features.iter().collect();
+                if let Some(component) = first_principal_component(&features) {
+                    markers.insert(COMMON_COMPONENT_KEY, serde_cbor::to_vec(&component)?);
+                    let component = Arc::new(component);
+                    store.common_component = Some(component.clone());
+                    new_component = Some(component);
+                }
+            }
+        } else if store.metadata.contains_key(COMMON_COMPONENT_KEY)? {
+            // Only prompts with text are embedded again without the component
+            markers.remove(COMMON_COMPONENT_KEY);
+            component_dropped = true;
+        }
+
+        if distance_changed || vectors_changed || new_component.is_some() || component_dropped {
+            println!("Embedding existing prompts again");
+            let (_, _, dropped) = store.reembed_with(
+                |vector| {
+                    if let Some(component) = &new_component {
+                        remove_component(vector, component);
+                    }
+                    // Prompts without text which were normalised stay that way
+                    if distance == Distance::Cosine {
+                        normalize_vector(vector);
+                    }
+                },
+                markers,
+            )?;
+            if dropped > 0 {
+                println!(
+                    "Dropped {} prompts without text, whose vectors don't match the word vectors",
+                    dropped
+                );
+            }
+            return Ok(store);
+        }
+        store.metadata.apply_batch(markers)?;
 
         println!("Preparing HNSW");
-        let mut hnsw = Hnsw::new(Euclidean);
-        let mut searcher = Searcher::default();
+        let (index, changed) = load_index(
+            &store.index_path,
+            &store.prompt_vectors,
+            &store.metadata,
+            &store.index_log,
+            distance,
+        )?;
+
+        // Prompts which were removed are only dropped from the index when it
+        // is rebuilt, so do that once they make up a significant part of it
+        let removed_prompts = index.len().saturating_sub(store.prompts.len());
+        let index_len = index.len();
+        *store.index_lock.write().unwrap() = index;
 
-        for pair in database.iter() {
-            let (serialized_vector, _) = pair?;
-            let vector = serde_cbor::from_slice(&serialized_vector)?;
-            hnsw.insert(vector, &mut searcher);
+        if removed_prompts > index_len / REMOVED_PROMPTS_REBUILD_FRACTION {
+            println!(
+                "Rebuilding HNSW without {} removed prompts",
+                removed_prompts
+            );
+            store.rebuild_index()?;
+        } else {
+            store
+                .removed_prompts
+                .store(removed_prompts, Ordering::Relaxed);
+            if changed {
+                println!("Saving HNSW");
+                store.save_index()?;
+            }
         }
 
-        let hnsw_lock = Arc::new(RwLock::new(hnsw));
-        let searcher_lock = Arc::new(RwLock::new(searcher));
+        Ok(store)
+    }
 
-        Ok(ResponseStore {
-            vectors: vectors_arc,
-            database,
-            hnsw_lock,
-            searcher_lock,
-        })
+    /// Rewrite responses from earlier versions of the database in the current
+    /// format.
+    fn migrate(&self) -> Result<(), StoreError> {
+        let version = schema_version(&self.database, &self.metadata)?;
+
+        if version < 2 {
+            println!("Migrating responses to record their provenance");
+            for pair in self.database.iter() {
+                let (serialized_vector, serialized_responses) = pair?;
+
+                // Responses migrated before an interruption are already done
+                if serde_cbor::from_slice::<Vec<StoredResponse>>(&serialized_responses).is_ok() {
+                    continue;
+                }
+                let legacy_responses: Vec<LegacyResponse> =
+                    serde_cbor::from_slice(&serialized_responses)?;
+
+                let responses: Vec<StoredResponse> = legacy_responses
+                    .into_iter()
+                    .map(|response| StoredResponse {
+                        body: response.body,
+                        provenance: Provenance {
+                            event_id: response.event_id,
+                            prompt_event_id: response.prompt_event_id,
+                            ..Provenance::default()
+                        },
+                    })
+                    .collect();
+                self.database
+                    .insert(serialized_vector, serde_cbor::to_vec(&responses)?)?;
+            }
+
+            self.metadata
+                .insert(SCHEMA_VERSION_KEY, &2_u64.to_be_bytes()[..])?;
+        }
+
+        // Responses used to be kept under the vectors of their prompts, and
+        // the event links and index referred to them by those vectors
+        if version < 3 {
+            println!("Migrating responses to be kept by prompt");
+
+            // Anything here was left behind by an interrupted migration, which
+            // starts again from the beginning
+            self.prompts.clear()?;
+            self.prompt_ids.clear()?;
+            self.prompt_vectors.clear()?;
+            self.events.clear()?;
+
+            for pair in self.database.iter() {
+                let (serialized_vector, serialized_responses) = pair?;
+                let responses: Vec<StoredResponse> = serde_cbor::from_slice(&serialized_responses)?;
+
+                // Different prompts could have had the same vector
+                let mut by_text: HashMap<Option<String>, Vec<StoredResponse>> = HashMap::new();
+                for response in responses {
+                    by_text
+                        .entry(response.provenance.prompt.clone())
+                        .or_default()
+                        .push(response);
+                }
+
+                for (text, responses) in by_text {
+                    let existing_id = match &text {
+                        Some(text) => self.prompt_ids.get(text)?.map(|id| read_id(&id)),
+                        None => None,
+                    };
+
+                    let prompt_id = match existing_id {
+                        Some(prompt_id) => prompt_id,
+                        None => {
+                            let prompt_id = self.database.generate_id()?;
+                            if let Some(text) = &text {
+                                self.prompt_ids.insert(text, &prompt_id.to_be_bytes()[..])?;
+                            }
+                            self.prompt_vectors
+                                .insert(prompt_id.to_be_bytes(), serialized_vector.clone())?;
+                            prompt_id
+                        }
+                    };
+
+                    push_responses(&self.prompts, prompt_id, text.as_deref(), &responses)?;
+                    link_events(&self.events, prompt_id, &responses)?;
+                }
+            }
+
+            // The responses are only removed from where they used to be once
+            // the migration is recorded as finished
+            reset_index_log(&self.metadata, &self.index_log)?;
+            self.metadata
+                .insert(SCHEMA_VERSION_KEY, &3_u64.to_be_bytes()[..])?;
+            self.metadata.flush()?;
+        }
+
+        // Which may not have happened if the migration was interrupted
+        if !self.database.is_empty() {
+            self.database.clear()?;
+        }
+
+        self.metadata
+            .insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION.to_be_bytes()[..])?;
+        Ok(())
+    }
+
+    fn weigh_tokens(&self, utterance: &str) -> Result<Vec<(String, f64)>, StoreError> {
+        let tokens = tokenize(utterance);
+
+        match self.embedding.weighting {
+            Weighting::Mean => Ok(tokens.into_iter().map(|token| (token, 1.0)).collect()),
+            Weighting::Sif => {
+                let total = read_count(self.metadata.get(TOTAL_WORDS_KEY)?.as_deref());
+
+                tokens
+                    .into_iter()
+                    .map(|token| {
+                        let count = self.word_counts.get(token.to_lowercase())?;
+                        let frequency = match total {
+                            0 => 0.0,
+                            total => read_count(count.as_deref()) as f64 / total as f64,
+                        };
+                        let weight = sif_weight(self.embedding.smoothing, frequency);
+                        Ok::<_, StoreError>((token, weight))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    fn utterance_to_vector(&self, utterance: &str) -> Result<Vector, StoreError> {
+        let tokens = self.weigh_tokens(utterance)?;
+        let mut vector =
+            tokens_to_vector(&self.vectors, &tokens).ok_or(StoreError::NoPromptVector)?;
+
+        if let Some(component) = &self.common_component {
+            remove_component(&mut vector, component);
+        }
+        if self.embedding.distance == Distance::Cosine {
+            normalize_vector(&mut vector);
+        }
+
+        Ok(vector)
     }
 
-    pub fn insert(&self, prompt: &str, response: Body) -> Result<(), StoreError> {
-        let vector =
-            utterance_to_vector(&self.vectors, prompt).ok_or(StoreError::NoPromptVector)?;
-        let serialized_vector = serde_cbor::to_vec(&vector)?;
+    /// Learn a response to a prompt, recording where it came from so that it
+    /// can be audited or removed later.
+    pub fn insert(
+        &self,
+        prompt: &str,
+        response: Body,
+        provenance: Provenance,
+    ) -> Result<(), StoreError> {
+        let response = StoredResponse {
+            body: response,
+            provenance,
+        };
+        self.insert_stored(prompt, response, true)
+    }
+
+    /// Learn many responses at once, skipping those whose prompts have no
+    /// vector and those which were already learned from the same source or
+    /// event. Returns how many were learned, and how many were already known.
+    pub fn insert_all(
+        &self,
+        responses: impl IntoIterator<Item = (String, Body, Provenance)>,
+    ) -> Result<(usize, usize), StoreError> {
+        let mut inserted = 0;
+        let mut duplicates = 0;
+        for (prompt, body, provenance) in responses {
+            let source = provenance.source.clone();
+            let already_imported = match &source {
+                Some(source) => self.imported_sources.contains_key(source)?,
+                None => false,
+            };
+            let already_learned = match &provenance.event_id {
+                Some(event_id) => self.has_learned_from(event_id)?,
+                None => false,
+            };
+            if already_imported || already_learned {
+                duplicates += 1;
+                continue;
+            }
+
+            let response = StoredResponse { body, provenance };
+            match self.insert_stored(&prompt, response, false) {
+                Ok(()) => inserted += 1,
+                Err(StoreError::NoPromptVector) => continue,
+                Err(error) => return Err(error),
+            }
+
+            if let Some(source) = source {
+                self.imported_sources.insert(source, &[] as &[u8])?;
+            }
+        }
+
+        // Saving as responses are inserted would repeatedly rewrite the index
+        self.save_index()?;
+        Ok((inserted, duplicates))
+    }
+
+    pub fn save_index(&self) -> Result<(), StoreError> {
+        let index = self.index_lock.read().unwrap();
+        let generation = read_count(self.metadata.get(GENERATION_KEY)?.as_deref());
+        self.save_snapshot(&index, generation)
+    }
+
+    /// Write the index of the given generation, unless a later one has
+    /// already been written.
+    fn save_snapshot(&self, index: &Index, generation: u64) -> Result<(), StoreError> {
+        let mut saved_generation = self.saved_generation.lock().unwrap();
+        if *saved_generation > generation {
+            return Ok(());
+        }
+
+        write_index(&self.index_path, index, self.embedding.distance, generation)?;
+        *saved_generation = generation;
+        prune_index_log(&self.index_log, generation)
+    }
+
+    /// Build the index again from the vectors of the prompts which remain.
+    fn rebuild_index(&self) -> Result<(), StoreError> {
+        let mut index = self.index_lock.write().unwrap();
+        *index = build_index(&self.prompt_vectors, self.embedding.distance)?;
+        self.removed_prompts.store(0, Ordering::Relaxed);
+
+        let generation = reset_index_log(&self.metadata, &self.index_log)?;
+        self.save_snapshot(&index, generation)
+    }
+
+    fn insert_stored(
+        &self,
+        prompt: &str,
+        mut response: StoredResponse,
+        save_index: bool,
+    ) -> Result<(), StoreError> {
+        response.provenance.prompt = Some(prompt.to_string());
+
+        // Prompts which are already known keep the vector they were given
+        let mut new_vector = None;
+        let prompt_id = match self.prompt_ids.get(prompt)? {
+            Some(prompt_id) => read_id(&prompt_id),
+            None => {
+                let vector = self.utterance_to_vector(prompt)?;
+                let prompt_id = self.database.generate_id()?;
 
+                let swap = self.prompt_ids.compare_and_swap(
+                    prompt,
+                    None as Option<&[u8]>,
+                    Some(&prompt_id.to_be_bytes()[..]),
+                )?;
+                match swap {
+                    Ok(()) => {
+                        self.prompt_vectors
+                            .insert(prompt_id.to_be_bytes(), serde_cbor::to_vec(&vector)?)?;
+                        new_vector = Some(vector);
+                        prompt_id
+                    }
+                    // The same prompt was learned at the same time
+                    Err(CompareAndSwapError { current, .. }) => {
+                        current.map(|id| read_id(&id)).unwrap_or(prompt_id)
+                    }
+                }
+            }
+        };
+
+        let responses = slice::from_ref(&response);
+        push_responses(&self.prompts, prompt_id, Some(prompt), responses)?;
+        link_events(&self.events, prompt_id, responses)?;
+        count_words(&self.word_counts, &self.metadata, prompt, 1)?;
+
+        // Otherwise the prompt is already in the index
+        let vector = match new_vector {
+            Some(vector) => vector,
+            None => return Ok(()),
+        };
+
+        let mut index = self.index_lock.write().unwrap();
+        let mut searcher = self.searcher_lock.write().unwrap();
+
+        let generation = increment_count(&self.metadata, GENERATION_KEY.as_bytes(), 1)?;
+        self.index_log
+            .insert(generation.to_be_bytes(), &prompt_id.to_be_bytes()[..])?;
+        index.insert(prompt_id, vector, &mut searcher);
+
+        // Writing the index takes a while, so it is written in the background
+        // while holding only a read lock, which holds up learning but not
+        // responding
+        if save_index && generation % INDEX_SAVE_INTERVAL == 0 {
+            let store = self.clone();
+            thread::spawn(move || {
+                if let Err(error) = store.save_index() {
+                    eprintln!("Error saving HNSW: {}", error);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Embed every prompt again using the current vectors and settings,
+    /// returning how many prompts were embedded again and how many kept
+    /// their previous vectors.
+    pub fn reembed(&mut self) -> Result<(usize, usize, usize), StoreError> {
+        self.reembed_with(|_| {}, Batch::default())
+    }
+
+    /// Embed every prompt again, applying a transformation to the previous
+    /// vectors of prompts which can't be. Returns how many prompts were
+    /// embedded again, how many kept their previous vectors, and how many were
+    /// dropped since their previous vectors came from different word vectors.
+    ///
+    /// The new vectors replace the previous ones in a single step, along with
+    /// the given metadata, so that an interrupted run can start again from the
+    /// previous vectors.
+    fn reembed_with(
+        &mut self,
+        transform: impl Fn(&mut Vector),
+        mut metadata: Batch,
+    ) -> Result<(usize, usize, usize), StoreError> {
+        let current_tree = read_count(self.metadata.get(PROMPT_VECTORS_TREE_KEY)?.as_deref());
+        let next_tree = (current_tree as usize + 1) % PROMPT_VECTORS_TREES.len();
+
+        // Left behind by an interrupted run
+        self.database.drop_tree(PROMPT_VECTORS_TREES[next_tree])?;
+        let prompt_vectors = self.database.open_tree(PROMPT_VECTORS_TREES[next_tree])?;
+
+        let mut reembedded = 0;
+        let mut kept = 0;
+        let mut dropped = 0;
+
+        for pair in self.prompts.iter() {
+            let (prompt_id, serialized_prompt) = pair?;
+            let prompt: StoredPrompt = serde_cbor::from_slice(&serialized_prompt)?;
+
+            let vector = match prompt.text.map(|text| self.utterance_to_vector(&text)) {
+                Some(Ok(vector)) => {
+                    reembedded += 1;
+                    vector
+                }
+                // Prompts whose text wasn't recorded, or no longer has any
+                // known words, are better kept than forgotten
+                None | Some(Err(StoreError::NoPromptVector)) => {
+                    let mut vector: Vector = match self.prompt_vectors.get(&prompt_id)? {
+                        Some(serialized_vector) => serde_cbor::from_slice(&serialized_vector)?,
+                        None => continue,
+                    };
+                    if vector.len() != self.vectors.dimensionality() {
+                        dropped += 1;
+                        continue;
+                    }
+                    transform(&mut vector);
+                    kept += 1;
+                    vector
+                }
+                Some(Err(error)) => return Err(error),
+            };
+
+            prompt_vectors.insert(prompt_id, serde_cbor::to_vec(&vector)?)?;
+        }
+        prompt_vectors.flush()?;
+
+        // The saved index and logged prompts belong to the previous vectors
+        let generation = read_count(self.metadata.get(GENERATION_KEY)?.as_deref()) + 1;
+        metadata.insert(GENERATION_KEY, &generation.to_be_bytes()[..]);
+        metadata.insert(INDEX_LOG_START_KEY, &generation.to_be_bytes()[..]);
+        metadata.insert(
+            PROMPT_VECTORS_TREE_KEY,
+            &(next_tree as u64).to_be_bytes()[..],
+        );
+        self.metadata.apply_batch(metadata)?;
+        self.metadata.flush()?;
+
+        self.prompt_vectors = prompt_vectors;
         self.database
-            .fetch_and_update(serialized_vector, |serialized_responses| {
-                let mut responses = match serialized_responses {
-                    Some(r) => {
-                        serde_cbor::from_slice::<Vec<Body>>(r).expect("Deserializing responses")
+            .drop_tree(PROMPT_VECTORS_TREES[current_tree as usize])?;
+
+        self.rebuild_index()?;
+        Ok((reembedded, kept, dropped))
+    }
+
+    /// Remove the responses to a prompt which match a predicate, returning
+    /// the removed responses.
+    fn remove_responses(
+        &self,
+        prompt_id: u64,
+        predicate: impl Fn(&StoredResponse) -> bool,
+    ) -> Result<Vec<StoredResponse>, StoreError> {
+        let mut removed = Vec::new();
+        let mut text = None;
+
+        let remaining_prompt =
+            self.prompts
+                .update_and_fetch(prompt_id.to_be_bytes(), |serialized_prompt| {
+                    let prompt = serde_cbor::from_slice::<StoredPrompt>(serialized_prompt?)
+                        .expect("Deserializing prompt");
+
+                    let (matching, remaining): (Vec<_>, Vec<_>) = prompt
+                        .responses
+                        .into_iter()
+                        .partition(|response| predicate(response));
+                    removed = matching;
+                    text = prompt.text.clone();
+
+                    if remaining.is_empty() {
+                        None
+                    } else {
+                        let prompt = StoredPrompt {
+                            text: prompt.text,
+                            responses: remaining,
+                        };
+                        Some(serde_cbor::to_vec(&prompt).expect("Serializing prompt"))
                     }
-                    None => Vec::new(),
-                };
+                })?;
 
-                responses.push(response.clone());
+        // Events may be shared with responses which weren't removed
+        let remaining: Vec<StoredResponse> = match &remaining_prompt {
+            Some(remaining_prompt) => {
+                serde_cbor::from_slice::<StoredPrompt>(remaining_prompt)?.responses
+            }
+            None => Vec::new(),
+        };
+        let remaining_event_ids: HashSet<&str> = remaining
+            .iter()
+            .flat_map(StoredResponse::event_ids)
+            .collect();
+        for event_id in removed.iter().flat_map(StoredResponse::event_ids) {
+            if !remaining_event_ids.contains(event_id) {
+                self.events.remove(event_key(event_id, prompt_id))?;
+            }
+        }
 
-                let serialized_responses =
-                    serde_cbor::to_vec(&responses).expect("Serializing responses");
-                Some(serialized_responses)
+        if !removed.is_empty() {
+            if let Some(text) = &text {
+                let removed = removed.len() as u64;
+                uncount_words(&self.word_counts, &self.metadata, text, removed)?;
+            }
+        }
+
+        if !removed.is_empty() && remaining_prompt.is_none() {
+            if let Some(text) = text {
+                // The text may have been learned again as a new prompt since
+                let _ = self.prompt_ids.compare_and_swap(
+                    text,
+                    Some(&prompt_id.to_be_bytes()[..]),
+                    None as Option<&[u8]>,
+                )?;
+            }
+            self.prompt_vectors.remove(prompt_id.to_be_bytes())?;
+            self.removed_prompts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove every response with the given text, returning how many were
+    /// removed.
+    pub fn remove_by_content(&self, plain: &str) -> Result<usize, StoreError> {
+        let mut prompt_ids = Vec::new();
+        for pair in self.prompts.iter() {
+            let (prompt_id, serialized_prompt) = pair?;
+            let prompt: StoredPrompt = serde_cbor::from_slice(&serialized_prompt)?;
+            if prompt
+                .responses
+                .iter()
+                .any(|response| response.body.plain == plain)
+            {
+                prompt_ids.push(read_id(&prompt_id));
+            }
+        }
+
+        let mut removed = 0;
+        for prompt_id in prompt_ids {
+            removed += self
+                .remove_responses(prompt_id, |response| response.body.plain == plain)?
+                .len();
+        }
+        Ok(removed)
+    }
+
+    /// The IDs of the prompts which responses learned from or to an event are
+    /// kept under, along with the keys linking them to the event.
+    fn event_prompt_ids(&self, event_id: &str) -> Result<Vec<(Vec<u8>, u64)>, StoreError> {
+        let prefix = event_prefix(event_id);
+
+        let mut prompt_ids = Vec::new();
+        for pair in self.events.scan_prefix(&prefix) {
+            let (key, _) = pair?;
+            prompt_ids.push((key.to_vec(), read_id(&key[prefix.len()..])));
+        }
+        Ok(prompt_ids)
+    }
+
+    /// Whether a response has been learned from the given event.
+    pub fn has_learned_from(&self, event_id: &str) -> Result<bool, StoreError> {
+        for (_, prompt_id) in self.event_prompt_ids(event_id)? {
+            let prompt = match self.prompts.get(prompt_id.to_be_bytes())? {
+                Some(prompt) => serde_cbor::from_slice::<StoredPrompt>(&prompt)?,
+                None => continue,
+            };
+            if prompt
+                .responses
+                .iter()
+                .any(|response| response.provenance.event_id.as_deref() == Some(event_id))
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Remove the responses learned from or in response to the given event,
+    /// returning how many were removed.
+    pub fn remove_by_event(&self, event_id: &str) -> Result<usize, StoreError> {
+        let mut removed = 0;
+        for (key, prompt_id) in self.event_prompt_ids(event_id)? {
+            removed += self
+                .remove_responses(prompt_id, |response| {
+                    response.event_ids().any(|id| id == event_id)
+                })?
+                .len();
+            // In case the link was left behind by a response which is gone
+            self.events.remove(&key)?;
+        }
+        Ok(removed)
+    }
+
+    /// Update what was learned from an event which has been edited, returning
+    /// how many responses were changed.
+    ///
+    /// Responses learned from the event take its new content, and responses to
+    /// it are moved to the prompt's new content, unless that has no vector.
+    pub fn replace_event(&self, event_id: &str, body: &Body) -> Result<usize, StoreError> {
+        // Checked before anything is removed, so that responses to the event
+        // are kept under its previous content rather than lost
+        let can_move = match self.prompt_ids.get(&body.plain)? {
+            Some(_) => true,
+            None => match self.utterance_to_vector(&body.plain) {
+                Ok(_) => true,
+                Err(StoreError::NoPromptVector) => false,
+                Err(error) => return Err(error),
+            },
+        };
+
+        // A failure in one prompt shouldn't stop the others being updated
+        let mut changed = 0;
+        let mut first_error = None;
+        for (_, prompt_id) in self.event_prompt_ids(event_id)? {
+            match self.replace_event_in_prompt(prompt_id, event_id, body, can_move) {
+                Ok(count) => changed += count,
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(changed),
+        }
+    }
+
+    fn replace_event_in_prompt(
+        &self,
+        prompt_id: u64,
+        event_id: &str,
+        body: &Body,
+        can_move: bool,
+    ) -> Result<usize, StoreError> {
+        let moved = if can_move {
+            self.remove_responses(prompt_id, |response| {
+                response.provenance.prompt_event_id.as_deref() == Some(event_id)
+            })?
+        } else {
+            Vec::new()
+        };
+        let mut changed = moved.len();
+
+        let mut updated = 0;
+        self.prompts
+            .update_and_fetch(prompt_id.to_be_bytes(), |serialized_prompt| {
+                let mut prompt = serde_cbor::from_slice::<StoredPrompt>(serialized_prompt?)
+                    .expect("Deserializing prompt");
+
+                updated = 0;
+                for response in prompt.responses.iter_mut() {
+                    if response.provenance.event_id.as_deref() == Some(event_id) {
+                        response.body = body.clone();
+                        updated += 1;
+                    }
+                }
+
+                Some(serde_cbor::to_vec(&prompt).expect("Serializing prompt"))
             })?;
+        changed += updated;
 
-        let mut hnsw = self.hnsw_lock.write().unwrap();
-        let mut searcher = self.searcher_lock.write().unwrap();
-        hnsw.insert(vector, &mut searcher);
+        for mut response in moved {
+            // A response to itself is unlikely, but should stay consistent
+            if response.provenance.event_id.as_deref() == Some(event_id) {
+                response.body = body.clone();
+            }
+            self.insert_stored(&body.plain, response, true)?;
+        }
+
+        Ok(changed)
+    }
 
+    pub fn backfill_progress(&self, room_id: &str) -> Result<Option<BackfillProgress>, StoreError> {
+        match self.backfill.get(room_id)? {
+            Some(progress) => Ok(Some(serde_cbor::from_slice(&progress)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_backfill_progress(
+        &self,
+        room_id: &str,
+        progress: &BackfillProgress,
+    ) -> Result<(), StoreError> {
+        self.backfill
+            .insert(room_id, serde_cbor::to_vec(progress)?)?;
         Ok(())
     }
 
-    pub fn respond(&self, prompt: &str) -> Result<Body, StoreError> {
-        let vector =
-            utterance_to_vector(&self.vectors, prompt).ok_or(StoreError::NoPromptVector)?;
+    /// Record the start of learning from the history of a room, unless that
+    /// has already started. Returns whether it was started.
+    pub fn start_backfill(
+        &self,
+        room_id: &str,
+        progress: &BackfillProgress,
+    ) -> Result<bool, StoreError> {
+        let swap = self.backfill.compare_and_swap(
+            room_id,
+            None as Option<&[u8]>,
+            Some(serde_cbor::to_vec(progress)?),
+        )?;
+        Ok(swap.is_ok())
+    }
+
+    /// Whether responses learned in one room may be used in another.
+    fn is_shared(&self, learned_in: Option<&str>, room_id: Option<&str>) -> bool {
+        // Responses learned before rooms were recorded can't be attributed
+        let learned_in = match learned_in {
+            Some(learned_in) => learned_in,
+            None => return true,
+        };
+        if Some(learned_in) == room_id {
+            return true;
+        }
+
+        match self.rooms.get(learned_in) {
+            Some(room) => match room.privacy.unwrap_or(self.responses.privacy) {
+                Privacy::Shared => true,
+                Privacy::Private => room_id.map_or(false, |room_id| {
+                    room.share_with.iter().any(|shared| shared == room_id)
+                }),
+            },
+            None => self.responses.privacy == Privacy::Shared,
+        }
+    }
+
+    /// Pool the responses to the closest prompts whose responses may be used
+    /// in a room, weighting them so that closer prompts are more likely to be
+    /// chosen. Also returns whether the neighbours were enough to find every
+    /// candidate.
+    fn gather_candidates(
+        &self,
+        index: &Index,
+        neighbours: &[Neighbor<u64>],
+        room_id: Option<&str>,
+    ) -> Result<(Vec<Candidate>, bool), StoreError> {
+        let mut prompts = 0;
+        let mut nearest_distance = None;
+        let mut candidates = Vec::new();
+        for neighbour in neighbours {
+            if prompts == self.responses.candidates {
+                return Ok((candidates, true));
+            }
+
+            let prompt_id = index.prompt_ids[neighbour.index];
+            let prompt = match self.prompts.get(prompt_id.to_be_bytes())? {
+                Some(prompt) => prompt,
+                None => continue,
+            };
+            let prompt: StoredPrompt = serde_cbor::from_slice(&prompt)?;
+            let responses: Vec<StoredResponse> = prompt
+                .responses
+                .into_iter()
+                .filter(|response| self.is_shared(response.provenance.room_id.as_deref(), room_id))
+                .collect();
+            if responses.is_empty() {
+                continue;
+            }
+            prompts += 1;
+
+            let distance = f64::from_bits(neighbour.distance);
+            let nearest_distance = *nearest_distance.get_or_insert(distance);
+            if let Some(max_distance) = self.responses.max_distance {
+                if distance > max_distance {
+                    if candidates.is_empty() {
+                        return Err(StoreError::NoGoodMatch(distance));
+                    }
+                    return Ok((candidates, true));
+                }
+            }
+
+            let weight = (-(distance - nearest_distance) / self.responses.temperature).exp();
+            for response in responses {
+                candidates.push(Candidate {
+                    body: response.body,
+                    distance,
+                    weight,
+                });
+            }
+        }
 
-        let mut neighbours = [Neighbor {
-            index: !0,
-            distance: !0,
-        }];
+        let complete = prompts == self.responses.candidates;
+        Ok((candidates, complete))
+    }
 
-        let hnsw = self.hnsw_lock.read().unwrap();
+    /// Choose a response to a prompt sent in the given room, using only
+    /// responses which that room may see.
+    pub fn respond(&self, prompt: &str, room_id: Option<&str>) -> Result<Response, StoreError> {
+        let vector = self.utterance_to_vector(prompt)?;
+
+        let index = self.index_lock.read().unwrap();
         let mut searcher = self.searcher_lock.write().unwrap();
-        hnsw.nearest(&vector, 24, &mut searcher, &mut neighbours);
 
-        if neighbours[0].index == !0 {
+        // Removed prompts are still in the index, so enough neighbours are
+        // fetched that they can be skipped, along with extra in case some are
+        // private to other rooms
+        let removed_prompts = self.removed_prompts.load(Ordering::Relaxed);
+        let search_factor = if self.has_private_rooms {
+            PRIVATE_SEARCH_FACTOR
+        } else {
+            1
+        };
+        let mut count = self.responses.candidates * search_factor + removed_prompts;
+
+        // The search is widened until enough candidates are found, or the
+        // whole index has been searched
+        let candidates = loop {
+            let mut neighbours = vec![
+                Neighbor {
+                    index: !0,
+                    distance: !0,
+                };
+                count
+            ];
+            let ef = count.max(24);
+            let neighbours = index
+                .hnsw
+                .nearest(&vector, ef, &mut searcher, &mut neighbours);
+
+            let (candidates, complete) = self.gather_candidates(&index, neighbours, room_id)?;
+            if complete || count >= index.len() {
+                break candidates;
+            }
+            count = (count * 2).min(index.len());
+        };
+
+        if candidates.is_empty() {
             return Err(StoreError::NoResponses);
         }
 
-        let vector = hnsw.feature(neighbours[0].index);
-        let serialized_vector = serde_cbor::to_vec(&vector)?;
+        let candidate = candidates
+            .choose_weighted(&mut rand::thread_rng(), |candidate| candidate.weight)
+            .map_err(|_| StoreError::MissingResponses)?;
+
+        Ok(Response {
+            body: candidate.body.clone(),
+            distance: candidate.distance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Responses as version 1 kept them, since `LegacyResponse` can only be
+    /// read.
+    #[derive(Serialize)]
+    struct V1Response {
+        plain: &'static str,
+        html: Option<&'static str>,
+        event_id: Option<&'static str>,
+        prompt_event_id: Option<&'static str>,
+    }
+
+    /// Create an empty data directory, along with a configuration using a
+    /// small set of vectors.
+    fn test_config(name: &str) -> Config {
+        let directory = env::temp_dir().join(format!("axyn-test-{}", name));
+        if directory.exists() {
+            fs::remove_dir_all(&directory).unwrap();
+        }
+        fs::create_dir_all(&directory).unwrap();
+
+        let vectors_path = directory.join("vectors.vec");
+        fs::write(
+            &vectors_path,
+            "3 3\nhello 1 2 3\nworld 4 5 6\napple -1 -2 -3\n",
+        )
+        .unwrap();
+
+        let config_path = directory.join("config.toml");
+        let config = format!(
+            "data_directory = {:?}\n[vectors]\npath = {:?}\n",
+            directory.join("data"),
+            vectors_path
+        );
+        fs::write(&config_path, config).unwrap();
+
+        let config = Config::load(Some(&config_path)).unwrap();
+        config.data_directory.create().unwrap();
+        config
+    }
+
+    /// Load the store from a database which has been filled in. It isn't
+    /// opened again, since it stays locked for a moment after being closed.
+    fn load_store(config: &Config, database: Db) -> ResponseStore {
+        let vectors = load_vectors(&config.vectors).unwrap();
+        ResponseStore::from_database(config, vectors, database).unwrap()
+    }
+
+    fn stored_vector(vector: &[f64]) -> Vec<u8> {
+        serde_cbor::to_vec(&vector).unwrap()
+    }
+
+    fn body(plain: &str) -> Body {
+        Body {
+            plain: plain.to_string(),
+            html: None,
+        }
+    }
+
+    fn learned(store: &ResponseStore) -> Vec<(Option<String>, String, Option<String>)> {
+        let reader = ResponseReader {
+            prompts: store.prompts.clone(),
+        };
+        let mut responses: Vec<_> = reader
+            .responses()
+            .map(|response| {
+                let (prompt, body, provenance) = response.unwrap();
+                (prompt, body.plain, provenance.event_id)
+            })
+            .collect();
+        responses.sort();
+        responses
+    }
+
+    #[test]
+    fn migrates_from_version_1() {
+        let config = test_config("version-1");
+        let database = sled::open(config.data_directory.responses()).unwrap();
+        let responses = [
+            V1Response {
+                plain: "Hi",
+                html: Some("<b>Hi</b>"),
+                event_id: Some("$hi"),
+                prompt_event_id: Some("$hello"),
+            },
+            V1Response {
+                plain: "Hey",
+                html: None,
+                event_id: Some("$hey"),
+                prompt_event_id: Some("$hello"),
+            },
+        ];
+        database
+            .insert(
+                stored_vector(&[1.0, 2.0, 3.0]),
+                serde_cbor::to_vec(&responses).unwrap(),
+            )
+            .unwrap();
+
+        let store = load_store(&config, database);
+        assert_eq!(
+            learned(&store),
+            [
+                (None, "Hey".to_string(), Some("$hey".to_string())),
+                (None, "Hi".to_string(), Some("$hi".to_string())),
+            ]
+        );
+        assert!(store.database.is_empty());
+        assert_eq!(
+            schema_version(&store.database, &store.metadata).unwrap(),
+            SCHEMA_VERSION
+        );
+
+        // Prompts without text keep their vectors
+        assert_eq!(store.respond("hello", None).unwrap().distance, 0.0);
+
+        assert_eq!(store.remove_by_event("$hi").unwrap(), 1);
+        assert_eq!(store.remove_by_event("$hello").unwrap(), 1);
+        assert!(learned(&store).is_empty());
+    }
+
+    #[test]
+    fn migrates_from_version_2() {
+        let config = test_config("version-2");
+        let database = sled::open(config.data_directory.responses()).unwrap();
+        let response = |plain: &str, event_id: &str, prompt: Option<&str>| StoredResponse {
+            body: body(plain),
+            provenance: Provenance {
+                event_id: Some(event_id.to_string()),
+                prompt_event_id: Some(format!("{}-prompt", event_id)),
+                prompt: prompt.map(str::to_string),
+                ..Provenance::default()
+            },
+        };
+
+        // Prompts with the same vector were kept together
+        let responses = [
+            response("Hi", "$hi", Some("hello")),
+            response("Hey", "$hey", Some("Hello")),
+            response("Yo", "$yo", None),
+        ];
+        database
+            .insert(
+                stored_vector(&[1.0, 2.0, 3.0]),
+                serde_cbor::to_vec(&responses).unwrap(),
+            )
+            .unwrap();
+        let responses = [response("Pear", "$pear", Some("apple"))];
+        database
+            .insert(
+                stored_vector(&[-1.0, -2.0, -3.0]),
+                serde_cbor::to_vec(&responses).unwrap(),
+            )
+            .unwrap();
+
+        let metadata = database.open_tree("metadata").unwrap();
+        metadata
+            .insert(SCHEMA_VERSION_KEY, &2_u64.to_be_bytes()[..])
+            .unwrap();
 
-        let responses = self.database.get(&serialized_vector)?;
-        let responses = responses.ok_or(StoreError::MissingResponses)?;
-        let responses: Vec<Body> = serde_cbor::from_slice(&responses)?;
+        let store = load_store(&config, database);
+        let text = |text: &str| Some(text.to_string());
+        assert_eq!(
+            learned(&store),
+            [
+                (None, "Yo".to_string(), text("$yo")),
+                (text("Hello"), "Hey".to_string(), text("$hey")),
+                (text("apple"), "Pear".to_string(), text("$pear")),
+                (text("hello"), "Hi".to_string(), text("$hi")),
+            ]
+        );
+        assert!(store.database.is_empty());
+        assert_eq!(store.prompt_ids.len(), 3);
 
-        let response = responses.choose(&mut rand::thread_rng());
-        let response = response.ok_or(StoreError::MissingResponses)?;
+        let response = store.respond("apple", None).unwrap();
+        assert_eq!(response.body.plain, "Pear");
+        assert_eq!(response.distance, 0.0);
 
-        Ok(response.clone())
+        assert_eq!(store.remove_by_event("$hi-prompt").unwrap(), 1);
+        assert_eq!(store.remove_by_event("$yo").unwrap(), 1);
+        assert_eq!(store.remove_by_event("$pear").unwrap(), 1);
+        assert_eq!(
+            learned(&store),
+            [(text("Hello"), "Hey".to_string(), text("$hey"))]
+        );
     }
 }
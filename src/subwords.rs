@@ -0,0 +1,42 @@
+use std::iter::once;
+
+const MIN_LENGTH: usize = 3;
+const MAX_LENGTH: usize = 6;
+
+/// fastText's variant of 32 bit FNV-1a, which sign extends each byte.
+fn hash(ngram: &str) -> u32 {
+    let mut hash: u32 = 2_166_136_261;
+    for &byte in ngram.as_bytes() {
+        hash ^= byte as i8 as u32;
+        hash = hash.wrapping_mul(16_777_619);
+    }
+    hash
+}
+
+/// Hash each character n-gram of a word into one of `buckets` buckets, in the
+/// same way as fastText.
+pub fn subword_buckets(word: &str, buckets: usize) -> Vec<usize> {
+    if buckets == 0 {
+        return Vec::new();
+    }
+
+    // The boundary markers let prefixes and suffixes be told apart
+    let word = format!("<{}>", word);
+    let boundaries: Vec<usize> = word
+        .char_indices()
+        .map(|(index, _)| index)
+        .chain(once(word.len()))
+        .collect();
+
+    let mut result = Vec::new();
+    for (start_index, &start) in boundaries.iter().enumerate() {
+        for length in MIN_LENGTH..=MAX_LENGTH {
+            match boundaries.get(start_index + length) {
+                Some(&end) => result.push(hash(&word[start..end]) as usize % buckets),
+                None => break,
+            }
+        }
+    }
+
+    result
+}
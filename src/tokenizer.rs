@@ -0,0 +1,182 @@
+extern crate lazy_regex;
+use lazy_regex::{lazy_regex, Lazy, Regex};
+
+extern crate unicode_segmentation;
+use unicode_segmentation::UnicodeSegmentation;
+
+static URL_REGEX: Lazy<Regex> = lazy_regex!(r"^(?:[a-zA-Z][a-zA-Z0-9+.-]*://|www\.)\S+$");
+
+// Matrix user IDs, room aliases and room IDs
+static MATRIX_ID_REGEX: Lazy<Regex> = lazy_regex!(r"^[@#!+][^:\s]+:\S+$");
+
+/// Suffixes which are split from the preceding word when the whole word has
+/// no vector, as in Penn Treebank tokenization.
+const CONTRACTIONS: &[&str] = &["n't", "'s", "'m", "'re", "'ve", "'ll", "'d"];
+
+fn is_emoji(character: char) -> bool {
+    matches!(
+        character as u32,
+        0x1F000..=0x1FAFF | 0x2300..=0x23FF | 0x2600..=0x27BF | 0x2B00..=0x2BFF
+    )
+}
+
+fn is_word(segment: &str) -> bool {
+    segment
+        .chars()
+        .any(|character| character.is_alphanumeric() || is_emoji(character))
+}
+
+/// Split an utterance into words, numbers and emoji.
+///
+/// Punctuation and whitespace are discarded, as are links and Matrix IDs
+/// since they are rarely in the vocabulary and say little about the meaning.
+pub fn tokenize(utterance: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+
+    for chunk in utterance.split_whitespace() {
+        if URL_REGEX.is_match(chunk) || MATRIX_ID_REGEX.is_match(chunk) {
+            continue;
+        }
+
+        for segment in chunk.split_word_bounds() {
+            if is_word(segment) {
+                tokens.push(segment.replace('’', "'"));
+            }
+        }
+    }
+
+    tokens
+}
+
+fn split_contraction(token: &str) -> Option<(&str, &str)> {
+    CONTRACTIONS.iter().find_map(|suffix| {
+        let split = token.len().checked_sub(suffix.len())?;
+        if split > 0 && token.is_char_boundary(split) && token[split..].eq_ignore_ascii_case(suffix)
+        {
+            Some(token.split_at(split))
+        } else {
+            None
+        }
+    })
+}
+
+fn lookup_word<T>(word: &str, lookup: &mut impl FnMut(&str) -> Option<T>) -> Option<T> {
+    lookup(word).or_else(|| {
+        let lowercase = word.to_lowercase();
+        if lowercase == word {
+            None
+        } else {
+            lookup(&lowercase)
+        }
+    })
+}
+
+/// Find the vocabulary entries which represent a token, trying its exact
+/// form, then lowercase, then its parts if it is a contraction.
+pub fn lookup_token<T>(token: &str, mut lookup: impl FnMut(&str) -> Option<T>) -> Vec<T> {
+    if let Some(entry) = lookup_word(token, &mut lookup) {
+        return vec![entry];
+    }
+
+    match split_contraction(token) {
+        Some((stem, suffix)) => [stem, suffix]
+            .into_iter()
+            .filter_map(|part| lookup_word(part, &mut lookup))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn vocabulary() -> HashSet<&'static str> {
+        [
+            "hello", "Hello", "world", "do", "n't", "it", "'s", "I", "'m", "can't", "42", "🎉",
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn resolve(token: &str) -> Vec<&'static str> {
+        let vocabulary = vocabulary();
+        lookup_token(token, |word| vocabulary.get(word).copied())
+    }
+
+    #[test]
+    fn strips_punctuation() {
+        assert_eq!(
+            tokenize("hello! hello, (world)."),
+            ["hello", "hello", "world"]
+        );
+    }
+
+    #[test]
+    fn splits_on_any_whitespace() {
+        assert_eq!(tokenize("hello\nworld\thello"), ["hello", "world", "hello"]);
+    }
+
+    #[test]
+    fn keeps_contractions_together() {
+        assert_eq!(tokenize("I don't know"), ["I", "don't", "know"]);
+        assert_eq!(tokenize("it’s"), ["it's"]);
+    }
+
+    #[test]
+    fn removes_links_and_mentions() {
+        assert_eq!(
+            tokenize("@axyn:example.org see https://example.org/a?b=c and www.example.org"),
+            ["see", "and"]
+        );
+        assert_eq!(tokenize("join #room:example.org"), ["join"]);
+    }
+
+    #[test]
+    fn keeps_numbers_and_emoji() {
+        assert_eq!(tokenize("42 🎉!"), ["42", "🎉"]);
+    }
+
+    #[test]
+    fn handles_non_latin_words() {
+        assert_eq!(tokenize("привет, мир"), ["привет", "мир"]);
+    }
+
+    #[test]
+    fn prefers_exact_case() {
+        assert_eq!(resolve("Hello"), ["Hello"]);
+    }
+
+    #[test]
+    fn falls_back_to_lowercase() {
+        assert_eq!(resolve("WORLD"), ["world"]);
+    }
+
+    #[test]
+    fn splits_unknown_contractions() {
+        assert_eq!(resolve("don't"), ["do", "n't"]);
+        assert_eq!(resolve("It's"), ["it", "'s"]);
+        assert_eq!(resolve("I'm"), ["I", "'m"]);
+    }
+
+    #[test]
+    fn keeps_known_contractions() {
+        assert_eq!(resolve("can't"), ["can't"]);
+    }
+
+    #[test]
+    fn resolves_tokenized_utterance() {
+        let resolved: Vec<&str> = tokenize("Hello, WORLD! Don't panic 🎉")
+            .iter()
+            .flat_map(|token| resolve(token))
+            .collect();
+        assert_eq!(resolved, ["Hello", "world", "do", "n't", "🎉"]);
+    }
+
+    #[test]
+    fn unknown_words_resolve_to_nothing() {
+        assert!(resolve("spaceship").is_empty());
+    }
+}
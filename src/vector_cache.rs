@@ -0,0 +1,373 @@
+//! A compact binary copy of the word vectors, which can be memory mapped
+//! rather than parsed on every startup.
+//!
+//! All numbers are little endian. The file is laid out as:
+//!
+//! - A header of [`HEADER_LENGTH`] bytes, described by the `*_OFFSET` constants
+//! - The vectors, as «number of vectors» × «dimensionality» `f32`s
+//! - For each vector, the `u64` offset of its word within the word list, plus a
+//!   final offset marking the end of the list
+//! - The index of each vector, as `u32`s, sorted by their words
+//! - The word list, as concatenated UTF-8 strings
+//! - For each subword bucket, the number of words which contributed to it, as
+//!   `u32`s
+//! - The mean vector of each subword bucket, as «number of buckets» ×
+//!   «dimensionality» `f32`s
+
+use std::{
+    cmp::Ordering,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+extern crate memmap2;
+use memmap2::Mmap;
+
+use crate::{
+    subwords::subword_buckets,
+    vectors::{Vector, VectorFormat},
+};
+
+const MAGIC: &[u8; 8] = b"AXYNVEC\0";
+const VERSION: u32 = 3;
+
+const VERSION_OFFSET: usize = 8;
+const DIMENSIONALITY_OFFSET: usize = 12;
+const ROWS_OFFSET: usize = 16;
+const INDEX_OFFSET: usize = 24;
+const SOURCE_LENGTH_OFFSET: usize = 32;
+const SOURCE_MODIFIED_OFFSET: usize = 40;
+const BUCKETS_OFFSET: usize = 48;
+const SUBWORDS_OFFSET: usize = 56;
+const FORMAT_OFFSET: usize = 64;
+const HEADER_LENGTH: usize = 72;
+
+/// Identifies the version of the source file which a cache was created from,
+/// and the format it was read as, so that the cache can be recreated if either
+/// changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceInfo {
+    length: u64,
+    modified: u64,
+    format: u64,
+}
+
+impl SourceInfo {
+    pub fn of(path: &Path, format: VectorFormat) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        Ok(SourceInfo {
+            length: metadata.len(),
+            modified,
+            format: format as u64,
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary: OsString = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+pub struct Vectors {
+    map: Mmap,
+    dimensionality: usize,
+    rows: usize,
+    word_offsets: usize,
+    sorted_rows: usize,
+    words: usize,
+    buckets: usize,
+    bucket_counts: usize,
+    bucket_vectors: usize,
+}
+
+impl Vectors {
+    /// Open a cache, returning `None` if it doesn't exist or doesn't match
+    /// the given source file and number of subword buckets.
+    pub fn open(path: &Path, source: SourceInfo, buckets: usize) -> io::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        // Safety: caches are only ever replaced by renaming a new file over
+        // the top, so the mapped file is never modified
+        let map = unsafe { Mmap::map(&file)? };
+
+        if map.len() < HEADER_LENGTH
+            || &map[..VERSION_OFFSET] != MAGIC
+            || read_u32(&map, VERSION_OFFSET) != VERSION
+            || read_u64(&map, SOURCE_LENGTH_OFFSET) != source.length
+            || read_u64(&map, SOURCE_MODIFIED_OFFSET) != source.modified
+            || read_u64(&map, FORMAT_OFFSET) != source.format
+            || read_u64(&map, BUCKETS_OFFSET) != buckets as u64
+        {
+            return Ok(None);
+        }
+
+        let dimensionality = read_u32(&map, DIMENSIONALITY_OFFSET) as usize;
+        let rows = read_u64(&map, ROWS_OFFSET) as usize;
+        let word_offsets = read_u64(&map, INDEX_OFFSET) as usize;
+        let sorted_rows = word_offsets + (rows + 1) * 8;
+        let words = sorted_rows + rows * 4;
+        let bucket_counts = read_u64(&map, SUBWORDS_OFFSET) as usize;
+        let bucket_vectors = bucket_counts + buckets * 4;
+
+        // A cache which was only partially written is treated as missing
+        if word_offsets != HEADER_LENGTH + rows * dimensionality * 4
+            || map.len() < words
+            || bucket_counts != words + read_u64(&map, word_offsets + rows * 8) as usize
+            || map.len() != bucket_vectors + buckets * dimensionality * 4
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(Vectors {
+            map,
+            dimensionality,
+            rows,
+            word_offsets,
+            sorted_rows,
+            words,
+            buckets,
+            bucket_counts,
+            bucket_vectors,
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    fn word(&self, row: usize) -> &[u8] {
+        let start = read_u64(&self.map, self.word_offsets + row * 8) as usize;
+        let end = read_u64(&self.map, self.word_offsets + (row + 1) * 8) as usize;
+        &self.map[self.words + start..self.words + end]
+    }
+
+    fn read_vector(&self, start: usize) -> Vector {
+        let end = start + self.dimensionality * 4;
+
+        self.map[start..end]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()) as f64)
+            .collect()
+    }
+
+    fn vector(&self, row: usize) -> Vector {
+        self.read_vector(HEADER_LENGTH + row * self.dimensionality * 4)
+    }
+
+    pub fn get(&self, word: &str) -> Option<Vector> {
+        let word = word.as_bytes();
+
+        // Binary search over the rows, which are sorted by their words
+        let mut low = 0;
+        let mut high = self.rows;
+        while low < high {
+            let middle = (low + high) / 2;
+            let row = read_u32(&self.map, self.sorted_rows + middle * 4) as usize;
+
+            match self.word(row).cmp(word) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Some(self.vector(row)),
+            }
+        }
+
+        None
+    }
+
+    /// Approximate the vector of a word which is not in the vocabulary, using
+    /// the words which share its character n-grams.
+    ///
+    /// This averages the mean vectors of the word's n-gram buckets, which is
+    /// cruder than fastText's own subword vectors, as those are trained.
+    pub fn get_from_subwords(&self, word: &str) -> Option<Vector> {
+        let mut total: Option<Vector> = None;
+        let mut count = 0;
+
+        for bucket in subword_buckets(word, self.buckets) {
+            // Skip n-grams which didn't appear in any known word
+            if read_u32(&self.map, self.bucket_counts + bucket * 4) == 0 {
+                continue;
+            }
+
+            let vector = self.read_vector(self.bucket_vectors + bucket * self.dimensionality * 4);
+            match &mut total {
+                Some(total) => {
+                    for (total_value, value) in total.iter_mut().zip(vector) {
+                        *total_value += value;
+                    }
+                }
+                None => total = Some(vector),
+            }
+            count += 1;
+        }
+
+        let mut total = total?;
+        for value in total.iter_mut() {
+            *value /= count as f64;
+        }
+        Some(total)
+    }
+}
+
+/// Writes a cache one vector at a time, so that the full set of vectors never
+/// needs to be held in memory.
+pub struct CacheWriter {
+    path: PathBuf,
+    temporary_path: PathBuf,
+    file: BufWriter<File>,
+    source: SourceInfo,
+    dimensionality: usize,
+    words: Vec<String>,
+    buckets: usize,
+    bucket_counts: Vec<u32>,
+    bucket_totals: Vec<f32>,
+}
+
+impl CacheWriter {
+    pub fn create(
+        path: &Path,
+        source: SourceInfo,
+        dimensionality: usize,
+        buckets: usize,
+    ) -> io::Result<Self> {
+        let temporary_path = temporary_path(path);
+        let mut file = BufWriter::new(File::create(&temporary_path)?);
+
+        // The header is filled in once everything else has been written
+        file.write_all(&[0; HEADER_LENGTH])?;
+
+        Ok(CacheWriter {
+            path: path.to_owned(),
+            temporary_path,
+            file,
+            source,
+            dimensionality,
+            words: Vec::new(),
+            buckets,
+            bucket_counts: vec![0; buckets],
+            bucket_totals: vec![0.0; buckets * dimensionality],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    pub fn push(&mut self, word: String, vector: &[f32]) -> io::Result<()> {
+        assert_eq!(vector.len(), self.dimensionality);
+
+        for value in vector {
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+
+        for bucket in subword_buckets(&word, self.buckets) {
+            self.bucket_counts[bucket] += 1;
+
+            let start = bucket * self.dimensionality;
+            let totals = &mut self.bucket_totals[start..start + self.dimensionality];
+            for (total, value) in totals.iter_mut().zip(vector) {
+                *total += value;
+            }
+        }
+
+        self.words.push(word);
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let rows = self.words.len();
+        let index_offset = HEADER_LENGTH + rows * self.dimensionality * 4;
+
+        let mut offset: u64 = 0;
+        self.file.write_all(&offset.to_le_bytes())?;
+        for word in &self.words {
+            offset += word.len() as u64;
+            self.file.write_all(&offset.to_le_bytes())?;
+        }
+
+        let mut sorted_rows: Vec<u32> = (0..rows as u32).collect();
+        sorted_rows.sort_by(|&a, &b| {
+            let a = self.words[a as usize].as_bytes();
+            let b = self.words[b as usize].as_bytes();
+            a.cmp(b)
+        });
+        for row in sorted_rows {
+            self.file.write_all(&row.to_le_bytes())?;
+        }
+
+        for word in &self.words {
+            self.file.write_all(word.as_bytes())?;
+        }
+
+        let subwords_offset = index_offset + (rows + 1) * 8 + rows * 4 + offset as usize;
+        for count in &self.bucket_counts {
+            self.file.write_all(&count.to_le_bytes())?;
+        }
+        for (bucket, totals) in self
+            .bucket_totals
+            .chunks_exact(self.dimensionality)
+            .enumerate()
+        {
+            let count = self.bucket_counts[bucket].max(1) as f32;
+            for total in totals {
+                self.file.write_all(&(total / count).to_le_bytes())?;
+            }
+        }
+
+        let mut header = [0; HEADER_LENGTH];
+        header[..VERSION_OFFSET].copy_from_slice(MAGIC);
+        header[VERSION_OFFSET..DIMENSIONALITY_OFFSET].copy_from_slice(&VERSION.to_le_bytes());
+        header[DIMENSIONALITY_OFFSET..ROWS_OFFSET]
+            .copy_from_slice(&(self.dimensionality as u32).to_le_bytes());
+        header[ROWS_OFFSET..INDEX_OFFSET].copy_from_slice(&(rows as u64).to_le_bytes());
+        header[INDEX_OFFSET..SOURCE_LENGTH_OFFSET]
+            .copy_from_slice(&(index_offset as u64).to_le_bytes());
+        header[SOURCE_LENGTH_OFFSET..SOURCE_MODIFIED_OFFSET]
+            .copy_from_slice(&self.source.length.to_le_bytes());
+        header[SOURCE_MODIFIED_OFFSET..BUCKETS_OFFSET]
+            .copy_from_slice(&self.source.modified.to_le_bytes());
+        header[BUCKETS_OFFSET..SUBWORDS_OFFSET]
+            .copy_from_slice(&(self.buckets as u64).to_le_bytes());
+        header[SUBWORDS_OFFSET..FORMAT_OFFSET]
+            .copy_from_slice(&(subwords_offset as u64).to_le_bytes());
+        header[FORMAT_OFFSET..HEADER_LENGTH].copy_from_slice(&self.source.format.to_le_bytes());
+
+        let mut file = self.file.into_inner().map_err(|error| error.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+
+        fs::rename(&self.temporary_path, &self.path)
+    }
+}
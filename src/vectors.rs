@@ -1,14 +1,24 @@
 use std::{
-    collections::HashMap,
     fs::File,
     io,
     io::{BufRead, BufReader},
     num,
+    path::Path,
 };
 
 extern crate quick_error;
 use quick_error::quick_error;
 
+extern crate serde;
+use serde::{Deserialize, Serialize};
+
+pub use crate::vector_cache::Vectors;
+use crate::{
+    config::VectorsConfig,
+    tokenizer::lookup_token,
+    vector_cache::{CacheWriter, SourceInfo},
+};
+
 quick_error! {
     #[derive(Debug)]
     pub enum VectorLoadError {
@@ -26,6 +36,9 @@ quick_error! {
         MissingWord {
             display("expected word at start of line")
         }
+        InvalidWord {
+            display("expected word to be valid UTF-8")
+        }
         ParseFloatError(error: num::ParseFloatError) {
             from(error: num::ParseFloatError) -> (error)
             source(error)
@@ -37,19 +50,53 @@ quick_error! {
         MissingVectors(expected: usize, actual: usize) {
             display("expected to load {} vectors, got {}", expected, actual)
         }
+        NoVectors {
+            display("the file did not contain any vectors")
+        }
+        UnsupportedFastTextModel {
+            display("fastText .bin models are not supported, use the .vec file of the same vectors instead")
+        }
         IOError(error: io::Error) {
             from(error: io::Error) -> (error)
             source(error)
             display("failed to open file: {}", error)
         }
+        InvalidCache {
+            display("the vector cache was not valid after being written")
+        }
     }
 }
 
 pub type Vector = Vec<f64>;
-pub type Vectors = HashMap<String, Vector>;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorFormat {
+    /// Detect the format from the contents of the file.
+    Auto,
+    /// fastText's text format, as in `.vec` files: a header line of
+    /// «number of vectors» «dimensionality», followed by one word and its
+    /// vector per line. fastText's binary `.bin` models are not supported.
+    FastText,
+    /// GloVe's text format, which is the same as fastText's but without the
+    /// header line.
+    GloVe,
+    /// word2vec's binary format: the same header line as fastText, followed by
+    /// each word, a space and its vector as little endian `f32`s.
+    Word2Vec,
+}
+
+impl Default for VectorFormat {
+    fn default() -> Self {
+        VectorFormat::Auto
+    }
+}
+
+/// The first four bytes of a fastText `.bin` model.
+const FASTTEXT_MODEL_MAGIC: [u8; 4] = 793_712_314_u32.to_le_bytes();
 
 fn parse_header(line: &str) -> Result<(usize, usize), VectorLoadError> {
-    let mut elements = line.split(' ');
+    let mut elements = line.split_ascii_whitespace();
 
     let rows = elements
         .next()
@@ -68,45 +115,238 @@ fn parse_header(line: &str) -> Result<(usize, usize), VectorLoadError> {
     }
 }
 
-fn parse_vector(dimensionality: usize, line: &str) -> Result<(String, Vector), VectorLoadError> {
-    let mut elements = line.split(' ');
+fn parse_vector(line: &str) -> Result<(String, Vec<f32>), VectorLoadError> {
+    let mut elements = line.split_ascii_whitespace();
 
     let word = elements.next().ok_or(VectorLoadError::MissingWord)?;
 
-    let mut vector = Vec::with_capacity(dimensionality);
+    let mut vector = Vec::new();
     for element in elements {
         vector.push(element.parse()?);
     }
 
-    if vector.len() == dimensionality {
-        Ok((word.to_string(), vector))
+    Ok((word.to_string(), vector))
+}
+
+fn detect_format(path: &Path) -> Result<VectorFormat, VectorLoadError> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    if reader.fill_buf()?.starts_with(&FASTTEXT_MODEL_MAGIC) {
+        return Err(VectorLoadError::UnsupportedFastTextModel);
+    }
+
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let dimensionality = match parse_header(&header) {
+        Ok((_, dimensionality)) => dimensionality,
+        Err(_) => return Ok(VectorFormat::GloVe),
+    };
+
+    // With a header, the first vector tells us whether the file is text
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    let is_text = match std::str::from_utf8(&line) {
+        Ok(line) => line.split_ascii_whitespace().count() == dimensionality + 1,
+        Err(_) => false,
+    };
+
+    if is_text {
+        Ok(VectorFormat::FastText)
     } else {
-        Err(VectorLoadError::WrongDimensionality(dimensionality, vector.len()))
+        Ok(VectorFormat::Word2Vec)
     }
 }
 
-pub fn load_vectors() -> Result<Vectors, VectorLoadError> {
-    println!("Loading vectors");
+/// Reads the body of a word2vec binary file.
+struct Word2VecReader<R> {
+    reader: R,
+    dimensionality: usize,
+}
 
-    let file = File::open(env!("WORD2VEC_DATA"))?;
-    let mut lines = BufReader::new(file).lines();
+impl<R: BufRead> Word2VecReader<R> {
+    fn read_vector(&mut self) -> Result<Option<(String, Vec<f32>)>, VectorLoadError> {
+        // Vectors may be separated by newlines, which are not part of the word
+        while self.reader.fill_buf()?.first() == Some(&b'\n') {
+            self.reader.consume(1);
+        }
 
-    let header_line = lines.next().ok_or(VectorLoadError::MissingHeader)??;
-    let (rows, dimensionality) = parse_header(&header_line)?;
+        let mut word = Vec::new();
+        if self.reader.read_until(b' ', &mut word)? == 0 {
+            return Ok(None);
+        }
+        if word.pop() != Some(b' ') {
+            return Err(VectorLoadError::MissingWord);
+        }
 
-    let mut vectors = HashMap::with_capacity(rows);
-    for line in lines {
-        let (word, vector) = parse_vector(dimensionality, &line?)?;
-        vectors.insert(word, vector);
+        let word = String::from_utf8(word).map_err(|_| VectorLoadError::InvalidWord)?;
+
+        let mut bytes = vec![0; self.dimensionality * 4];
+        self.reader.read_exact(&mut bytes)?;
+        let vector = bytes
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+
+        Ok(Some((word, vector)))
     }
+}
 
-    if vectors.len() == rows {
-        Ok(vectors)
-    } else {
-        Err(VectorLoadError::MissingVectors(rows, vectors.len()))
+impl<R: BufRead> Iterator for Word2VecReader<R> {
+    type Item = Result<(String, Vec<f32>), VectorLoadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_vector().transpose()
+    }
+}
+
+/// Write vectors to a new cache, checking that they match the header of the
+/// file, if it had one.
+fn write_cache(
+    vectors: impl Iterator<Item = Result<(String, Vec<f32>), VectorLoadError>>,
+    header: Option<(usize, usize)>,
+    source: SourceInfo,
+    config: &VectorsConfig,
+) -> Result<(), VectorLoadError> {
+    let mut cache: Option<CacheWriter> = None;
+
+    for result in vectors {
+        let (word, vector) = result?;
+
+        let cache = match &mut cache {
+            Some(cache) => cache,
+            None => {
+                let dimensionality = match header {
+                    Some((_, dimensionality)) => dimensionality,
+                    None => vector.len(),
+                };
+                cache.insert(CacheWriter::create(
+                    &config.cache_path,
+                    source,
+                    dimensionality,
+                    config.subword_buckets,
+                )?)
+            }
+        };
+
+        if vector.len() != cache.dimensionality() {
+            return Err(VectorLoadError::WrongDimensionality(
+                cache.dimensionality(),
+                vector.len(),
+            ));
+        }
+
+        cache.push(word, &vector)?;
+    }
+
+    let cache = cache.ok_or(VectorLoadError::NoVectors)?;
+
+    match header {
+        Some((rows, _)) if rows != cache.len() => {
+            Err(VectorLoadError::MissingVectors(rows, cache.len()))
+        }
+        _ => Ok(cache.finish()?),
+    }
+}
+
+fn open_vectors(path: &Path) -> io::Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+fn convert_vectors(
+    format: VectorFormat,
+    source: SourceInfo,
+    config: &VectorsConfig,
+) -> Result<(), VectorLoadError> {
+    match format {
+        VectorFormat::Auto => {
+            let format = detect_format(&config.path)?;
+            println!("Detected vector format: {:?}", format);
+            convert_vectors(format, source, config)
+        }
+
+        VectorFormat::FastText => {
+            let mut lines = open_vectors(&config.path)?.lines();
+            let header_line = lines.next().ok_or(VectorLoadError::MissingHeader)??;
+            let header = parse_header(&header_line)?;
+
+            let vectors = lines.map(|line| parse_vector(&line?));
+            write_cache(vectors, Some(header), source, config)
+        }
+
+        VectorFormat::GloVe => {
+            let vectors = open_vectors(&config.path)?
+                .lines()
+                .map(|line| parse_vector(&line?));
+            write_cache(vectors, None, source, config)
+        }
+
+        VectorFormat::Word2Vec => {
+            let mut reader = open_vectors(&config.path)?;
+            let mut header_line = String::new();
+            if reader.read_line(&mut header_line)? == 0 {
+                return Err(VectorLoadError::MissingHeader);
+            }
+            let header = parse_header(&header_line)?;
+
+            let vectors = Word2VecReader {
+                reader,
+                dimensionality: header.1,
+            };
+            write_cache(vectors, Some(header), source, config)
+        }
+    }
+}
+
+/// Load vectors from the configured cache, first converting them from the
+/// source file if the cache is missing or out of date.
+pub fn load_vectors(config: &VectorsConfig) -> Result<Vectors, VectorLoadError> {
+    let source = SourceInfo::of(&config.path, config.format)?;
+
+    let vectors = match Vectors::open(&config.cache_path, source, config.subword_buckets)? {
+        Some(vectors) => vectors,
+        None => {
+            println!(
+                "Converting vectors from {} to {}",
+                config.path.display(),
+                config.cache_path.display()
+            );
+
+            convert_vectors(config.format, source, config)?;
+
+            Vectors::open(&config.cache_path, source, config.subword_buckets)?
+                .ok_or(VectorLoadError::InvalidCache)?
+        }
+    };
+
+    println!(
+        "Loaded {} vectors of dimensionality {}",
+        vectors.len(),
+        vectors.dimensionality()
+    );
+
+    Ok(vectors)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weighting {
+    /// Every word counts equally.
+    Mean,
+    /// Smooth inverse frequency: common words count for less than rare ones.
+    Sif,
+}
+
+impl Default for Weighting {
+    fn default() -> Self {
+        Weighting::Mean
     }
 }
 
+/// The weight of a word with the given frequency in a SIF sentence vector.
+pub fn sif_weight(smoothing: f64, frequency: f64) -> f64 {
+    smoothing / (smoothing + frequency)
+}
+
 fn add_vectors(a: &mut Vector, b: &Vector) {
     for (a_value, b_value) in a.iter_mut().zip(b) {
         *a_value += b_value;
@@ -126,6 +366,12 @@ fn sum_vectors(vectors: &[Vector]) -> Option<Vector> {
     Some(result)
 }
 
+fn multiply_vector(vector: &mut Vector, scalar: f64) {
+    for value in vector.iter_mut() {
+        *value *= scalar;
+    }
+}
+
 fn divide_vector(vector: &mut Vector, scalar: f64) {
     for value in vector.iter_mut() {
         *value /= scalar;
@@ -139,14 +385,73 @@ fn mean_vectors(vectors: &[Vector]) -> Option<Vector> {
     Some(total)
 }
 
-pub fn utterance_to_vector(vectors: &Vectors, utterance: &str) -> Option<Vector> {
+fn dot_product(a: &Vector, b: &Vector) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+const POWER_ITERATIONS: usize = 32;
+
+/// Estimate the first principal component of some vectors by power iteration.
+///
+/// As in the SIF paper, the vectors are not centred first, so this finds the
+/// direction which they most have in common.
+pub fn first_principal_component(vectors: &[&Vector]) -> Option<Vector> {
+    let dimensionality = vectors.first()?.len();
+    let mut component = vec![1.0; dimensionality];
+
+    for _ in 0..POWER_ITERATIONS {
+        let mut next = vec![0.0; dimensionality];
+        for vector in vectors {
+            let projection = dot_product(vector, &component);
+            for (next_value, value) in next.iter_mut().zip(vector.iter()) {
+                *next_value += projection * value;
+            }
+        }
+
+        let magnitude = dot_product(&next, &next).sqrt();
+        if magnitude == 0.0 {
+            return None;
+        }
+        divide_vector(&mut next, magnitude);
+        component = next;
+    }
+
+    Some(component)
+}
+
+/// Subtract the projection of a vector onto a unit length component.
+pub fn remove_component(vector: &mut Vector, component: &Vector) {
+    let projection = dot_product(vector, component);
+    for (value, component_value) in vector.iter_mut().zip(component) {
+        *value -= projection * component_value;
+    }
+}
+
+/// Scale a vector to unit length, leaving it unchanged if it is zero.
+pub fn normalize_vector(vector: &mut Vector) {
+    let magnitude = dot_product(vector, vector).sqrt();
+    if magnitude > 0.0 {
+        divide_vector(vector, magnitude);
+    }
+}
+
+/// Combine the vectors of some tokens into a single vector, scaling each by
+/// the weight given alongside the token.
+pub fn tokens_to_vector(vectors: &Vectors, tokens: &[(String, f64)]) -> Option<Vector> {
     let mut results: Vec<Vector> = Vec::new();
 
-    // TODO: Tokenize properly
-    for word in utterance.split(' ') {
-        let word = word.to_lowercase();
-        if let Some(vector) = vectors.get(&word) {
-            results.push(vector.clone());
+    for (token, weight) in tokens {
+        let mut token_vectors = lookup_token(token, |word| vectors.get(word));
+
+        // Words which are not in the vocabulary are approximated from their
+        // character n-grams instead
+        if token_vectors.is_empty() {
+            token_vectors.extend(vectors.get_from_subwords(&token.to_lowercase()));
+        }
+
+        for mut vector in token_vectors {
+            multiply_vector(&mut vector, *weight);
+            results.push(vector);
         }
     }
 